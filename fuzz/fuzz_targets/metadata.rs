@@ -0,0 +1,30 @@
+#![no_main]
+
+use dmi::dmi::RawDmi;
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+
+/// A blank spritesheet the fuzzed metadata is stored into, big enough for a handful of 32x32 sprites.
+fn blank_dmi() -> &'static RawDmi {
+	static BLANK_DMI: OnceLock<RawDmi> = OnceLock::new();
+	BLANK_DMI.get_or_init(|| {
+		let mut png = vec![];
+		image::DynamicImage::new_rgba8(128, 128)
+			.write_to(&mut png, image::ImageOutputFormat::Png)
+			.expect("Unable to encode png");
+		RawDmi::load(&png[..]).expect("Unable to load png")
+	})
+}
+
+fuzz_target!(|data: &[u8]| {
+	let mut raw_dmi = blank_dmi().clone();
+	raw_dmi.chunk_ztxt = match dmi::ztxt::create_ztxt_chunk(data) {
+		Ok(chunk) => Some(chunk),
+		Err(_) => return,
+	};
+	let mut dmi = vec![];
+	if raw_dmi.save(&mut dmi).is_err() {
+		return;
+	};
+	let _ = dmi::icon::Icon::load(&dmi[..]);
+});
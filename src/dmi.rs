@@ -11,6 +11,113 @@ use std::io::prelude::*;
 /// The PNG magic header
 pub const PNG_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
+/// Copies a DMI without its metadata, leaving a plain PNG of the spritesheet.
+/// The zTXt Description chunk and any tEXt or iTXt one are dropped, the image data is kept as-is.
+pub fn strip_metadata<R: Read, W: Write>(
+	reader: R,
+	writter: &mut W,
+) -> Result<usize, error::DmiError> {
+	let mut raw_dmi = RawDmi::load(reader)?;
+	raw_dmi.chunk_ztxt = None;
+	if let Some(other_chunks) = &mut raw_dmi.other_chunks {
+		other_chunks.retain(|chunk| !is_text_description(chunk));
+		if other_chunks.is_empty() {
+			raw_dmi.other_chunks = None;
+		};
+	};
+	raw_dmi.save(writter)
+}
+
+/// Whether the chunk is a tEXt or iTXt Description, which some tools write instead of the zTXt one BYOND uses.
+pub(crate) fn is_text_description(chunk: &chunk::RawGenericChunk) -> bool {
+	(&chunk.chunk_type == b"tEXt" || &chunk.chunk_type == b"iTXt")
+		&& chunk.data.starts_with(b"Description\0")
+}
+
+/// Extracts the text of a chunk matched by `is_text_description`.
+/// The iTXt language tag and translated keyword are skipped, and its text is inflated when flagged as compressed.
+pub(crate) fn read_text_description(
+	chunk: &chunk::RawGenericChunk,
+) -> Result<Vec<u8>, error::DmiError> {
+	let data = &chunk.data[b"Description\0".len()..];
+	if &chunk.chunk_type != b"iTXt" {
+		return Ok(data.to_vec());
+	};
+	let truncated = || {
+		error::DmiError::Generic(
+			"Failed to read iTXt Description: chunk data is truncated.".to_string(),
+		)
+	};
+	let (compression_flag, compression_method) = match data {
+		[flag, method, ..] => (*flag, *method),
+		_ => return Err(truncated()),
+	};
+	let mut fields = data[2..].splitn(3, |byte| *byte == 0);
+	let _language_tag = fields.next();
+	let _translated_keyword = fields.next();
+	let text = fields.next().ok_or_else(truncated)?;
+	match (compression_flag, compression_method) {
+		(0, _) => Ok(text.to_vec()),
+		(1, 0) => inflate::inflate_bytes_zlib(text).map_err(|message| {
+			error::DmiError::Generic(format!(
+				"Failed to read iTXt Description: unable to inflate text: {}",
+				message
+			))
+		}),
+		(1, method) => Err(error::DmiError::Generic(format!(
+			"Failed to read iTXt Description: unknown compression method {}.",
+			method
+		))),
+		(flag, _) => Err(error::DmiError::Generic(format!(
+			"Failed to read iTXt Description: invalid compression flag {}.",
+			flag
+		))),
+	}
+}
+
+/// Splits a PNG file, signature included, into its chunks in the order they're found, up to and including IEND.
+pub(crate) fn read_chunks(
+	dmi_bytes: &[u8],
+) -> Result<Vec<chunk::RawGenericChunk>, error::DmiError> {
+	let mut chunks = vec![];
+	// Index starts after the PNG header.
+	let mut index = 8;
+
+	loop {
+		if index + 12 > dmi_bytes.len() {
+			return Err(error::DmiError::Generic(
+				"Failed to load DMI. Buffer end reached without finding an IEND chunk.".to_string(),
+			));
+		}
+
+		let chunk_data_length = u32::from_be_bytes([
+			dmi_bytes[index],
+			dmi_bytes[index + 1],
+			dmi_bytes[index + 2],
+			dmi_bytes[index + 3],
+		]) as usize;
+
+		// 12 minimum necessary bytes from the chunk plus the data length.
+		let chunk_end = match (index + 12).checked_add(chunk_data_length) {
+			Some(chunk_end) if chunk_end <= dmi_bytes.len() => chunk_end,
+			_ => {
+				return Err(error::DmiError::Generic(format!(
+					"Failed to load DMI. Chunk data length ({}) exceeds the end of the buffer.",
+					chunk_data_length
+				)))
+			}
+		};
+		let raw_chunk = chunk::RawGenericChunk::load(&mut &dmi_bytes[index..chunk_end])?;
+		index = chunk_end;
+
+		let is_iend = &raw_chunk.chunk_type == b"IEND";
+		chunks.push(raw_chunk);
+		if is_iend {
+			return Ok(chunks);
+		};
+	}
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Default)]
 pub struct RawDmi {
 	pub header: [u8; 8],
@@ -56,44 +163,23 @@ impl RawDmi {
 		let mut chunk_ztxt = None;
 		let mut chunk_plte = None;
 		let mut chunks_idat = vec![];
-		let chunk_iend;
+		let mut chunk_iend = None;
 		let mut other_chunks = vec![];
 
-		// Index starts after the PNG header.
-		let mut index = 8;
-
-		loop {
-			if index + 12 > dmi_bytes.len() {
-				return Err(error::DmiError::Generic(
-					"Failed to load DMI. Buffer end reached without finding an IEND chunk.".to_string(),
-				));
-			}
-
-			let chunk_data_length = u32::from_be_bytes([
-				dmi_bytes[index],
-				dmi_bytes[index + 1],
-				dmi_bytes[index + 2],
-				dmi_bytes[index + 3],
-			]) as usize;
-
-			// 12 minimum necessary bytes from the chunk plus the data length.
-			let chunk_bytes = dmi_bytes[index..(index + 12 + chunk_data_length)].to_vec();
-			let raw_chunk = chunk::RawGenericChunk::load(&mut &*chunk_bytes)?;
-			index += 12 + chunk_data_length;
-
+		for raw_chunk in read_chunks(&dmi_bytes)? {
 			match &raw_chunk.chunk_type {
 				b"IHDR" => chunk_ihdr = Some(raw_chunk),
-				b"zTXt" => chunk_ztxt = Some(ztxt::RawZtxtChunk::try_from(raw_chunk)?),
+				// DMI metadata lives in the zTXt chunk with the Description keyword, other text chunks are kept as-is.
+				b"zTXt" if raw_chunk.data.starts_with(b"Description\0") => {
+					chunk_ztxt = Some(ztxt::RawZtxtChunk::try_from(raw_chunk)?)
+				}
 				b"PLTE" => chunk_plte = Some(raw_chunk),
 				b"IDAT" => chunks_idat.push(raw_chunk),
-				b"IEND" => {
-					chunk_iend = Some(iend::RawIendChunk::try_from(raw_chunk)?);
-					break;
-				}
+				b"IEND" => chunk_iend = Some(iend::RawIendChunk::try_from(raw_chunk)?),
 				_ => other_chunks.push(raw_chunk),
 			}
 		}
-		if chunk_ihdr == None {
+		if chunk_ihdr.is_none() {
 			return Err(error::DmiError::Generic(
 				"Failed to load DMI. Buffer end reached without finding an IHDR chunk.".to_string(),
 			));
@@ -108,6 +194,7 @@ impl RawDmi {
 			_ => Some(other_chunks),
 		};
 		let chunk_ihdr = chunk_ihdr.unwrap();
+		// `read_chunks` only returns once it reached the IEND chunk.
 		let chunk_iend = chunk_iend.unwrap();
 
 		Ok(RawDmi {
@@ -140,48 +227,39 @@ impl RawDmi {
 			)));
 		};
 
-		match &self.chunk_ztxt {
-			Some(chunk_ztxt) => {
-				let bytes_written = chunk_ztxt.save(&mut writter)?;
-				total_bytes_written += bytes_written;
-				if bytes_written < u32::from_be_bytes(chunk_ztxt.data_length) as usize + 12 {
-					return Err(error::DmiError::Generic(format!(
-						"Failed to save DMI. Buffer unable to hold the data, only {} bytes written.",
-						total_bytes_written
-					)));
-				};
-			}
-			None => (),
+		if let Some(chunk_ztxt) = &self.chunk_ztxt {
+			let bytes_written = chunk_ztxt.save(&mut writter)?;
+			total_bytes_written += bytes_written;
+			if bytes_written < u32::from_be_bytes(chunk_ztxt.data_length) as usize + 12 {
+				return Err(error::DmiError::Generic(format!(
+					"Failed to save DMI. Buffer unable to hold the data, only {} bytes written.",
+					total_bytes_written
+				)));
+			};
 		};
 
-		match &self.chunk_plte {
-			Some(chunk_plte) => {
-				let bytes_written = chunk_plte.save(&mut writter)?;
+		if let Some(chunk_plte) = &self.chunk_plte {
+			let bytes_written = chunk_plte.save(&mut writter)?;
+			total_bytes_written += bytes_written;
+			if bytes_written < u32::from_be_bytes(chunk_plte.data_length) as usize + 12 {
+				return Err(error::DmiError::Generic(format!(
+					"Failed to save DMI. Buffer unable to hold the data, only {} bytes written.",
+					total_bytes_written
+				)));
+			};
+		};
+
+		if let Some(other_chunks) = &self.other_chunks {
+			for chunk in other_chunks {
+				let bytes_written = chunk.save(&mut writter)?;
 				total_bytes_written += bytes_written;
-				if bytes_written < u32::from_be_bytes(chunk_plte.data_length) as usize + 12 {
+				if bytes_written < u32::from_be_bytes(chunk.data_length) as usize + 12 {
 					return Err(error::DmiError::Generic(format!(
 						"Failed to save DMI. Buffer unable to hold the data, only {} bytes written.",
 						total_bytes_written
 					)));
 				};
 			}
-			None => (),
-		};
-
-		match &self.other_chunks {
-			Some(other_chunks) => {
-				for chunk in other_chunks {
-					let bytes_written = chunk.save(&mut writter)?;
-					total_bytes_written += bytes_written;
-					if bytes_written < u32::from_be_bytes(chunk.data_length) as usize + 12 {
-						return Err(error::DmiError::Generic(format!(
-							"Failed to save DMI. Buffer unable to hold the data, only {} bytes written.",
-							total_bytes_written
-						)));
-					};
-				}
-			}
-			None => (),
 		}
 
 		for chunk in &self.chunks_idat {
@@ -2,6 +2,7 @@ use std::io;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum DmiError {
 	#[error("IO error")]
 	Io(#[from] io::Error),
@@ -17,6 +18,36 @@ pub enum DmiError {
 	InvalidChunkType { chunk_type: [u8; 4] },
 	#[error("CRC mismatch (stated {stated:?}, calculated {calculated:?})")]
 	CrcMismatch { stated: u32, calculated: u32 },
+	#[error("Unsupported DMI version: {0}")]
+	UnsupportedVersion(String),
+	#[error("Missing required field: {0}")]
+	MissingField(&'static str),
+	#[error("Field set more than once: {0}")]
+	DuplicateField(&'static str),
+	#[error("Invalid {setting} value {value:?}: {reason}")]
+	InvalidValue {
+		setting: &'static str,
+		value: String,
+		reason: &'static str,
+	},
+	#[error("Invalid number of directions: {0} (must be 1, 4 or 8)")]
+	InvalidDirs(u8),
+	#[error("Too many images: {frames} frames of {dirs} directions overflow the image count")]
+	ImageCountOverflow { frames: u32, dirs: u8 },
+	#[error("Invalid hotspot: expected 2 or 3 values, found {0}")]
+	HotspotLength(usize),
+	#[error("Invalid hotspot: index {index} out of range for a state of {image_count} images")]
+	HotspotIndex { index: u32, image_count: u32 },
+	#[error("Exceeded the {limit} limit: {value} (maximum {max})")]
+	ExceedsLimit {
+		limit: &'static str,
+		value: u64,
+		max: u64,
+	},
+	/// Only the line is reported, not a column: the metadata holds a single setting per line, which the line
+	/// points at, and `source` tells which part of it is wrong.
+	#[error("Error on line {line} of the DMI metadata")]
+	Metadata { line: usize, source: Box<DmiError> },
 	#[error("Dmi error: {0}")]
 	Generic(String),
 	#[error("Encoding error: {0}")]
@@ -24,3 +55,21 @@ pub enum DmiError {
 	#[error("Conversion error: {0}")]
 	Conversion(String),
 }
+
+impl DmiError {
+	/// The underlying error, looking past the `Metadata` wrapper that only adds the line it was found at.
+	pub fn kind(&self) -> &DmiError {
+		match self {
+			DmiError::Metadata { source, .. } => source.kind(),
+			error => error,
+		}
+	}
+
+	/// The line of the DMI metadata the error was found at, if it came from parsing it.
+	pub fn line(&self) -> Option<usize> {
+		match self {
+			DmiError::Metadata { line, .. } => Some(*line),
+			_ => None,
+		}
+	}
+}
@@ -1,35 +1,241 @@
 use super::error;
 use super::ztxt;
-use super::RawDmi;
+use super::{chunk, is_text_description, read_chunks, read_text_description, RawDmi, PNG_HEADER};
 
 use image::imageops;
 use image::GenericImageView;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io::prelude::*;
+use std::num::ParseIntError;
+use std::ops::Range;
+use std::str::FromStr;
 
-#[derive(Clone, Default)]
+/// Settings within the metadata are indented, normally with a tab. Some tools use spaces instead.
+const INDENTATION: &[char] = &['\t', ' '];
+
+#[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Icon {
 	pub version: DmiVersion,
 	pub width: u32,
 	pub height: u32,
 	pub states: Vec<IconState>,
+	/// Header settings this library doesn't know about, as `(setting, value)` pairs in the order they were found.
+	pub unknown_settings: Option<Vec<(String, String)>>,
+	/// `#` comment lines of the header, as `(position, line)` pairs in order. Each is written before the header
+	/// setting at that position, 0 being `version`, or after the last one once past it. Comments of the states
+	/// are kept in `IconState::comments`.
+	pub comments: Option<Vec<(usize, String)>>,
 }
 
 impl Icon {
 	pub fn load<R: Read>(reader: R) -> Result<Icon, error::DmiError> {
+		Icon::load_with_warnings(reader).map(|(icon, _)| icon)
+	}
+
+	/// Loads a DMI file like `load`, also returning what was tolerated while parsing its metadata.
+	pub fn load_with_warnings<R: Read>(reader: R) -> Result<(Icon, Vec<Warning>), error::DmiError> {
 		let raw_dmi = RawDmi::load(reader)?;
-		let chunk_ztxt = match &raw_dmi.chunk_ztxt {
-			Some(chunk) => chunk.clone(),
-			None => {
+		let mut warnings = vec![];
+		let mut icon = Icon::load_raw_meta(&raw_dmi, &mut warnings)?;
+
+		// Image time.
+		let mut reader = vec![];
+		raw_dmi.save(&mut reader)?;
+		let base_image = image::load_from_memory_with_format(&reader, image::ImageFormat::Png)?;
+
+		let width = icon.width;
+		let height = icon.height;
+		let width_in_states = columns(base_image.width(), width)?;
+		let mut index = 0;
+
+		for icon_state in icon.states.iter_mut() {
+			for _frame in 0..icon_state.frames {
+				for _dir in 0..icon_state.dirs {
+					let x = (index % width_in_states) * width;
+					//This operation rounds towards zero, truncating any fractional part of the exact result, essentially a floor() function.
+					let y = (index / width_in_states) * height;
+					icon_state
+						.images
+						.push(base_image.crop_imm(x, y, width, height));
+					index += 1;
+				}
+			}
+		}
+
+		Ok((icon, warnings))
+	}
+
+	/// Loads only the metadata of a DMI file, without decoding its spritesheet.
+	/// The reader may also hold the bare metadata text, from `# BEGIN DMI` to `# END DMI`, which is told apart
+	/// by the lack of a PNG header. The states of the returned icon hold no images.
+	pub fn load_meta<R: Read>(reader: R) -> Result<Icon, error::DmiError> {
+		Icon::load_meta_skipping(reader, None)
+	}
+
+	/// Loads the metadata of a DMI file like `load_meta`, leaving out the states that fail to parse instead of
+	/// failing altogether. Their errors are returned along with the icon. The header must still parse.
+	pub fn load_meta_lenient<R: Read>(
+		reader: R,
+	) -> Result<(Icon, Vec<error::DmiError>), error::DmiError> {
+		let mut skipped = vec![];
+		let icon = Icon::load_meta_skipping(reader, Some(&mut skipped))?;
+		Ok((icon, skipped))
+	}
+
+	/// Loads the metadata of a DMI file or bare metadata text. Given `skipped`, broken states are left out and
+	/// their errors added to it, rather than failing the whole load.
+	fn load_meta_skipping<R: Read>(
+		mut reader: R,
+		skipped: Option<&mut Vec<error::DmiError>>,
+	) -> Result<Icon, error::DmiError> {
+		let mut dmi_bytes = vec![];
+		reader.read_to_end(&mut dmi_bytes)?;
+		let mut states = vec![];
+		let mut icon = if dmi_bytes.starts_with(&PNG_HEADER) {
+			Icon::load_raw_states(
+				&RawDmi::load(&dmi_bytes[..])?,
+				&mut vec![],
+				skipped,
+				|icon_state| states.push(icon_state),
+			)?
+		} else {
+			Icon::parse_metadata(
+				&String::from_utf8(dmi_bytes)?,
+				None,
+				&mut vec![],
+				skipped,
+				|icon_state| states.push(icon_state),
+			)?
+		};
+		icon.states = states;
+		Ok(icon)
+	}
+
+	/// Parses bare metadata text like `load_meta`, also returning the byte range each state covers within `text`.
+	/// A span runs from the start of the `state = ` line to the end of its last setting, trailing comments and
+	/// blank lines excluded. Spans are in the same order as the states.
+	pub fn metadata_with_spans(text: &str) -> Result<(Icon, Vec<Range<usize>>), error::DmiError> {
+		let mut states = vec![];
+		let mut icon = Icon::parse_metadata(text, None, &mut vec![], None, |icon_state| {
+			states.push(icon_state)
+		})?;
+		icon.states = states;
+
+		// The text parsed, so the state lines can be picked out the same way the parser does.
+		let mut spans = vec![];
+		let mut current_span: Option<Range<usize>> = None;
+		let mut offset = 0;
+		for line in text.split_inclusive('\n') {
+			let line_start = offset;
+			offset += line.len();
+			let line = line.trim_end();
+			if line.is_empty() || is_comment(line) {
+				continue;
+			};
+			if ends_state(line) {
+				spans.extend(current_span.take());
+				if is_trailer(line) {
+					break;
+				};
+				current_span = Some(line_start..line_start + line.len());
+			} else if let Some(span) = &mut current_span {
+				span.end = line_start + line.len();
+			};
+		}
+		Ok((icon, spans))
+	}
+
+	/// Loads the metadata of a DMI file like `load_meta`, but hands each state to `on_state` as soon as
+	/// it's parsed instead of collecting them. The returned icon holds no states.
+	pub fn load_states<R: Read, F: FnMut(IconState)>(
+		reader: R,
+		on_state: F,
+	) -> Result<Icon, error::DmiError> {
+		let raw_dmi = RawDmi::load(reader)?;
+		Icon::load_raw_states(&raw_dmi, &mut vec![], None, on_state)
+	}
+
+	fn load_raw_meta(raw_dmi: &RawDmi, warnings: &mut Vec<Warning>) -> Result<Icon, error::DmiError> {
+		let mut states = vec![];
+		let mut icon = Icon::load_raw_states(raw_dmi, warnings, None, |icon_state| {
+			states.push(icon_state)
+		})?;
+		icon.states = states;
+		Ok(icon)
+	}
+
+	fn load_raw_states<F: FnMut(IconState)>(
+		raw_dmi: &RawDmi,
+		warnings: &mut Vec<Warning>,
+		skipped: Option<&mut Vec<error::DmiError>>,
+		on_state: F,
+	) -> Result<Icon, error::DmiError> {
+		// The zTXt chunk BYOND writes takes precedence over a tEXt or iTXt one.
+		let text_description = raw_dmi
+			.other_chunks
+			.iter()
+			.flatten()
+			.find(|chunk| is_text_description(chunk));
+		let decompressed_text = match (&raw_dmi.chunk_ztxt, text_description) {
+			(Some(chunk_ztxt), _) => chunk_ztxt.data.decode()?,
+			(None, Some(chunk)) => read_text_description(chunk)?,
+			(None, None) => {
 				return Err(error::DmiError::Generic(
-					"Error loading icon: no zTXt chunk found.".to_string(),
+					"Error loading icon: no zTXt, tEXt or iTXt Description chunk found.".to_string(),
 				))
 			}
 		};
-		let decompressed_text = chunk_ztxt.data.decode()?;
 		let decompressed_text = String::from_utf8(decompressed_text)?;
-		let mut decompressed_text = decompressed_text.lines();
 
+		// The spritesheet dimensions are the first two fields of the IHDR chunk.
+		let ihdr_data = &raw_dmi.chunk_ihdr.data;
+		if ihdr_data.len() < 8 {
+			return Err(error::DmiError::Generic(format!(
+				"Error loading icon: IHDR chunk too short ({} bytes) to hold the image dimensions.",
+				ihdr_data.len()
+			)));
+		};
+		let img_width = u32::from_be_bytes([ihdr_data[0], ihdr_data[1], ihdr_data[2], ihdr_data[3]]);
+		let img_height = u32::from_be_bytes([ihdr_data[4], ihdr_data[5], ihdr_data[6], ihdr_data[7]]);
+
+		Icon::parse_metadata(
+			&decompressed_text,
+			Some((img_width, img_height)),
+			warnings,
+			skipped,
+			on_state,
+		)
+	}
+
+	/// Parses the DMI metadata text, checking it against the dimensions of the spritesheet it describes, if any.
+	/// Each state is handed to `on_state`, the returned icon holds none. Anything tolerated is added to `warnings`.
+	/// Errors are wrapped in `DmiError::Metadata` along with the line they were found at. Given `skipped`, broken
+	/// states are left out and their errors added to it instead.
+	fn parse_metadata<F: FnMut(IconState)>(
+		text: &str,
+		sheet_dimensions: Option<(u32, u32)>,
+		warnings: &mut Vec<Warning>,
+		skipped: Option<&mut Vec<error::DmiError>>,
+		on_state: F,
+	) -> Result<Icon, error::DmiError> {
+		// Some tools prepend a byte order mark to the text.
+		let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+		let mut lines = MetadataLines::new(text);
+		Icon::parse_metadata_lines(&mut lines, sheet_dimensions, warnings, skipped, on_state)
+			.map_err(|error| at_line(lines.line_number, error))
+	}
+
+	fn parse_metadata_lines<F: FnMut(IconState)>(
+		decompressed_text: &mut MetadataLines<'_>,
+		sheet_dimensions: Option<(u32, u32)>,
+		warnings: &mut Vec<Warning>,
+		mut skipped: Option<&mut Vec<error::DmiError>>,
+		mut on_state: F,
+	) -> Result<Icon, error::DmiError> {
 		let current_line = decompressed_text.next();
 		if current_line != Some("# BEGIN DMI") {
 			return Err(error::DmiError::Generic(format!(
@@ -40,54 +246,60 @@ impl Icon {
 
 		let current_line = match decompressed_text.next() {
 			Some(thing) => thing,
-			None => {
-				return Err(error::DmiError::Generic(
-					"Error loading icon: no version header found.".to_string(),
-				))
-			}
+			None => return Err(error::DmiError::MissingField("version")),
 		};
+		// Comments are written back before the header setting they were found above, counting from `version`.
+		let mut comments: Vec<(usize, String)> = decompressed_text.take_comments(0).collect();
 		let split_version: Vec<&str> = current_line.split_terminator(" = ").collect();
-		if split_version.len() != 2 || split_version[0] != "version" {
+		if split_version.len() != 2 || !split_version[0].eq_ignore_ascii_case("version") {
 			return Err(error::DmiError::Generic(format!(
 				"Error loading icon: improper version header found: {:#?}",
 				split_version
 			)));
 		};
-		let version = split_version[1].to_string();
+		warn_capitalized(split_version[0], warnings);
+		let version = split_version[1].parse::<DmiVersion>()?;
+		if !version.is_supported() {
+			return Err(error::DmiError::UnsupportedVersion(version.to_string()));
+		};
 
 		let current_line = match decompressed_text.next() {
 			Some(thing) => thing,
-			None => {
-				return Err(error::DmiError::Generic(
-					"Error loading icon: no width found.".to_string(),
-				))
-			}
+			None => return Err(error::DmiError::MissingField("width")),
 		};
+		comments.extend(decompressed_text.take_comments(1));
 		let split_version: Vec<&str> = current_line.split_terminator(" = ").collect();
-		if split_version.len() != 2 || split_version[0] != "\twidth" {
+		if split_version.len() != 2
+			|| !split_version[0]
+				.trim_start_matches(INDENTATION)
+				.eq_ignore_ascii_case("width")
+		{
 			return Err(error::DmiError::Generic(format!(
 				"Error loading icon: improper width found: {:#?}",
 				split_version
 			)));
 		};
-		let width = split_version[1].parse::<u32>()?;
+		warn_capitalized(split_version[0].trim_start_matches(INDENTATION), warnings);
+		let width = parse_unsigned::<u32>("width", split_version[1])?;
 
 		let current_line = match decompressed_text.next() {
 			Some(thing) => thing,
-			None => {
-				return Err(error::DmiError::Generic(
-					"Error loading icon: no height found.".to_string(),
-				))
-			}
+			None => return Err(error::DmiError::MissingField("height")),
 		};
+		comments.extend(decompressed_text.take_comments(2));
 		let split_version: Vec<&str> = current_line.split_terminator(" = ").collect();
-		if split_version.len() != 2 || split_version[0] != "\theight" {
+		if split_version.len() != 2
+			|| !split_version[0]
+				.trim_start_matches(INDENTATION)
+				.eq_ignore_ascii_case("height")
+		{
 			return Err(error::DmiError::Generic(format!(
 				"Error loading icon: improper height found: {:#?}",
 				split_version
 			)));
 		};
-		let height = split_version[1].parse::<u32>()?;
+		warn_capitalized(split_version[0].trim_start_matches(INDENTATION), warnings);
+		let height = parse_unsigned::<u32>("height", split_version[1])?;
 
 		if width == 0 || height == 0 {
 			return Err(error::DmiError::Generic(format!(
@@ -96,248 +308,390 @@ impl Icon {
 			)));
 		};
 
-		// Image time.
-		let mut reader = vec![];
-		raw_dmi.save(&mut reader)?;
-		let base_image = image::load_from_memory_with_format(&reader, image::ImageFormat::Png)?;
-
-		let dimensions = base_image.dimensions();
-		let img_width = dimensions.0;
-		let img_height = dimensions.1;
+		let max_possible_states = match sheet_dimensions {
+			Some((img_width, img_height)) => {
+				if img_width == 0 || img_height == 0 || img_width % width != 0 || img_height % height != 0 {
+					return Err(error::DmiError::Generic(format!("Error loading icon: invalid image width ({}) / height ({}) values. Missmatch with metadata width ({}) / height ({}).", img_width, img_height, width, height)));
+				};
 
-		if img_width == 0 || img_height == 0 || img_width % width != 0 || img_height % height != 0 {
-			return Err(error::DmiError::Generic(format!("Error loading icon: invalid image width ({}) / height ({}) values. Missmatch with metadata width ({}) / height ({}).", img_width, img_height, width, height)));
+				let width_in_states = columns(img_width, width)?;
+				let height_in_states = img_height / height;
+				// Counted in 64 bits, as sheets of many small sprites may hold more than fits an u32.
+				Some(width_in_states as u64 * height_in_states as u64)
+			}
+			// Bare metadata text, without a spritesheet to hold the states.
+			None => None,
 		};
 
-		let width_in_states = img_width / width;
-		let height_in_states = img_height / height;
-		let max_possible_states = width_in_states * height_in_states;
-
 		let mut index = 0;
 
-		let mut current_line = match decompressed_text.next() {
-			Some(thing) => thing,
-			None => {
-				return Err(error::DmiError::Generic(
-					"Error loading icon: no DMI trailer nor states found.".to_string(),
-				))
-			}
-		};
+		let mut unknown_settings = None;
+		let mut header_lines = 3;
+		let mut current_line = loop {
+			let current_line = match decompressed_text.next() {
+				Some(thing) => thing,
+				None => {
+					return Err(error::DmiError::Generic(
+						"Error loading icon: no DMI trailer nor states found.".to_string(),
+					))
+				}
+			};
+			if ends_state(current_line) {
+				break current_line;
+			};
+			comments.extend(decompressed_text.take_comments(header_lines));
+			header_lines += 1;
 
-		let mut states = vec![];
+			let split_version: Vec<&str> = current_line.splitn(2, " = ").collect();
+			if split_version.len() != 2 {
+				return Err(error::DmiError::Generic(format!(
+					"Error loading icon: improper header setting found: {:#?}",
+					split_version
+				)));
+			};
+			let key = split_version[0].trim_start_matches(INDENTATION);
+			if matches!(
+				key.to_ascii_lowercase().as_str(),
+				"version" | "width" | "height"
+			) {
+				return Err(error::DmiError::Generic(format!(
+					"Error loading icon: {} header setting found twice.",
+					key
+				)));
+			};
+			// Header settings newer than this library, kept just like the unknown state settings.
+			warnings.push(Warning::UnknownSetting {
+				state: None,
+				setting: key.to_string(),
+			});
+			let setting = (key.to_string(), split_version[1].to_string());
+			unknown_settings = match unknown_settings {
+				None => Some(vec![setting]),
+				Some(mut thing) => {
+					thing.push(setting);
+					Some(thing)
+				}
+			};
+		};
 
 		loop {
-			if current_line.contains("# END DMI") {
+			if is_trailer(current_line) {
 				break;
 			};
 
-			let split_version: Vec<&str> = current_line.split_terminator(" = ").collect();
-			if split_version.len() != 2 || split_version[0] != "state" {
+			// By the time the whole state is read, the next line has been peeked at already.
+			let state_line_number = decompressed_text.line_number;
+			let icon_state = Icon::parse_state(current_line, decompressed_text, warnings).and_then(|icon_state| {
+				let image_count = icon_state
+					.checked_image_count()
+					.map_err(|error| at_line(state_line_number, error))?;
+				if let Some(max_possible_states) = max_possible_states {
+					if index + image_count as u64 > max_possible_states {
+						return Err(at_line(state_line_number, error::DmiError::Generic(format!("Error loading icon: metadata settings exceeded the maximum number of states possible ({}).", max_possible_states))));
+					};
+				};
+				Ok(icon_state)
+			});
+			match icon_state {
+				Ok(icon_state) => {
+					index += icon_state.image_count() as u64;
+					on_state(icon_state);
+				}
+				Err(error) => {
+					let error = at_line(decompressed_text.line_number, error);
+					match &mut skipped {
+						Some(skipped) => skipped.push(error),
+						None => return Err(error),
+					};
+					decompressed_text.skip_state();
+				}
+			};
+
+			current_line = match decompressed_text.next() {
+				Some(thing) => thing,
+				None => {
+					return Err(error::DmiError::Generic(
+						"Error loading icon: no DMI trailer found.".to_string(),
+					))
+				}
+			};
+		}
+
+		// Those left were found after the header of a file without states.
+		comments.extend(decompressed_text.take_comments(header_lines));
+
+		Ok(Icon {
+			version,
+			width,
+			height,
+			states: vec![],
+			unknown_settings,
+			comments: if comments.is_empty() {
+				None
+			} else {
+				Some(comments)
+			},
+		})
+	}
+
+	/// Parses the state starting at `state_line` along with its settings, up to the line starting the next
+	/// state or the trailer, which is left in `decompressed_text`. Errors about the state as a whole are reported
+	/// at the line of `state_line`.
+	fn parse_state(
+		state_line: &str,
+		decompressed_text: &mut MetadataLines<'_>,
+		warnings: &mut Vec<Warning>,
+	) -> Result<IconState, error::DmiError> {
+		let state_line_number = decompressed_text.line_number;
+		// Comments right above the state go along with it.
+		let mut comments: Vec<(usize, String)> = decompressed_text.take_comments(0).collect();
+
+		// Only the first ` = ` separates the key, the value may hold more, such as in state names.
+		let split_version: Vec<&str> = state_line.splitn(2, " = ").collect();
+		if split_version.len() != 2 || !split_version[0].eq_ignore_ascii_case("state") {
+			return Err(error::DmiError::Generic(format!(
+				"Error loading icon: improper state found: {:#?}",
+				split_version
+			)));
+		};
+		warn_capitalized(split_version[0], warnings);
+
+		let name = split_version[1].as_bytes();
+		if !name.starts_with(b"\"") || !name.ends_with(b"\"") {
+			return Err(error::DmiError::Generic(format!("Error loading icon: invalid name icon_state found in metadata, should be preceded and succeeded by double-quotes (\"): {:#?}", name)));
+		};
+		let name = match name.len() {
+			0 | 1 => {
+				return Err(error::DmiError::Generic(format!(
+					"Error loading icon: invalid name icon_state found in metadata, improper size: {:#?}",
+					name
+				)))
+			}
+			2 => String::new(), //Only the quotes, empty name otherwise.
+			length => String::from_utf8(name[1..(length - 1)].to_vec())?, //Hacky way to trim. Blame the cool methods being nightly experimental.
+		};
+		// BYOND escapes quotes and backslashes within state names.
+		let name = unescape_state_name(&name);
+
+		let mut dirs = None;
+		let mut frames = None;
+		let mut delay = None;
+		let mut loop_flag = None;
+		let mut rewind = None;
+		let mut movement = None;
+		let mut hotspots = None;
+		let mut unknown_settings = None;
+		let mut state_lines = 1;
+
+		// The line ending the state is left for the caller.
+		while let Some(current_line) = decompressed_text.next_if(|line| !ends_state(line)) {
+			comments.extend(decompressed_text.take_comments(state_lines));
+			state_lines += 1;
+			let split_version: Vec<&str> = current_line.splitn(2, " = ").collect();
+			if split_version.len() != 2 {
 				return Err(error::DmiError::Generic(format!(
 					"Error loading icon: improper state found: {:#?}",
 					split_version
 				)));
 			};
 
-			let name = split_version[1].as_bytes();
-			if !name.starts_with(&[b'\"']) || !name.ends_with(&[b'\"']) {
-				return Err(error::DmiError::Generic(format!("Error loading icon: invalid name icon_state found in metadata, should be preceded and succeeded by double-quotes (\"): {:#?}", name)));
+			let key = split_version[0].trim_start_matches(INDENTATION);
+			// Some exporters capitalize the keys. Unknown ones are still kept as found.
+			let lowercase_key = key.to_ascii_lowercase();
+			// Repeated settings are most likely a copy-paste mistake, rather than silently keep the last one.
+			let duplicate = match lowercase_key.as_str() {
+				"dirs" if dirs.is_some() => Some("dirs"),
+				"frames" if frames.is_some() => Some("frames"),
+				"delay" if delay.is_some() => Some("delay"),
+				"loop" if loop_flag.is_some() => Some("loop"),
+				"rewind" if rewind.is_some() => Some("rewind"),
+				"movement" if movement.is_some() => Some("movement"),
+				_ => None,
+			};
+			if let Some(duplicate) = duplicate {
+				return Err(error::DmiError::DuplicateField(duplicate));
 			};
-			let name = match name.len() {
-				0 | 1 => {
+			match lowercase_key.as_str() {
+				"dirs" => dirs = Some(parse_unsigned::<u8>("dirs", split_version[1])?),
+				"frames" => {
+					let value = parse_unsigned::<u32>("frames", split_version[1])?;
+					if value == 0 {
+						return Err(error::DmiError::InvalidValue {
+							setting: "frames",
+							value: split_version[1].to_string(),
+							reason: "a state must have at least one frame",
+						});
+					};
+					frames = Some(value);
+				}
+				"delay" => {
+					let mut delay_vector = vec![];
+					let text_delays = split_version[1].split_terminator(',');
+					for text_entry in text_delays {
+						// Hand-edited files may have spaces around the commas.
+						delay_vector.push(text_entry.trim().parse::<f32>()?);
+					}
+					delay = Some(delay_vector);
+				}
+				"loop" => loop_flag = Some(parse_unsigned::<u32>("loop", split_version[1])?),
+				"rewind" => rewind = Some(parse_flag(split_version[1])?),
+				"movement" => movement = Some(parse_flag(split_version[1])?),
+				"version" => {
 					return Err(error::DmiError::Generic(format!(
-						"Error loading icon: invalid name icon_state found in metadata, improper size: {:#?}",
+						"Error loading icon: version setting found within the icon state \"{}\".",
 						name
 					)))
 				}
-				2 => String::new(), //Only the quotes, empty name otherwise.
-				length => String::from_utf8(name[1..(length - 1)].to_vec())?, //Hacky way to trim. Blame the cool methods being nightly experimental.
-			};
-
-			let mut dirs = None;
-			let mut frames = None;
-			let mut delay = None;
-			let mut loop_flag = None;
-			let mut rewind = None;
-			let mut movement = None;
-			let mut hotspot = None;
-			let mut unknown_settings = None;
-
-			loop {
-				current_line = match decompressed_text.next() {
-					Some(thing) => thing,
-					None => {
-						return Err(error::DmiError::Generic(
-							"Error loading icon: no DMI trailer found.".to_string(),
-						))
-					}
-				};
-
-				if current_line.contains("# END DMI") || current_line.contains("state = \"") {
-					break;
-				};
-				let split_version: Vec<&str> = current_line.split_terminator(" = ").collect();
-				if split_version.len() != 2 {
-					return Err(error::DmiError::Generic(format!(
-						"Error loading icon: improper state found: {:#?}",
-						split_version
-					)));
-				};
-
-				match split_version[0] {
-					"\tdirs" => dirs = Some(split_version[1].parse::<u8>()?),
-					"\tframes" => frames = Some(split_version[1].parse::<u32>()?),
-					"\tdelay" => {
-						let mut delay_vector = vec![];
-						let text_delays = split_version[1].split_terminator(',');
-						for text_entry in text_delays {
-							delay_vector.push(text_entry.parse::<f32>()?);
+				"hotspot" => {
+					// Hand-edited files may have spaces around the commas, as with delays.
+					let text_coordinates: Vec<&str> = split_version[1]
+						.split_terminator(',')
+						.map(str::trim)
+						.collect();
+					// Older files may leave out the index, which then defaults to the first frame.
+					let index = match text_coordinates.len() {
+						2 => 0,
+						3 => parse_unsigned::<u32>("hotspot", text_coordinates[2])?,
+						length => return Err(error::DmiError::HotspotLength(length)),
+					};
+					let hotspot = Hotspot {
+						// The coordinates may point outside the icon, only the index can't be negative.
+						x: parse_integer::<i32>("hotspot", text_coordinates[0])?,
+						y: parse_integer::<i32>("hotspot", text_coordinates[1])?,
+						index,
+					};
+					// A state may define a hotspot for each of its frames / directions.
+					hotspots = match hotspots {
+						None => Some(vec![hotspot]),
+						Some(mut thing) => {
+							thing.push(hotspot);
+							Some(thing)
 						}
-						delay = Some(delay_vector);
-					}
-					"\tloop" => loop_flag = Some(split_version[1].parse::<u32>()?),
-					"\trewind" => rewind = Some(split_version[1].parse::<u32>()?),
-					"\tmovement" => movement = Some(split_version[1].parse::<u32>()?),
-					"\thotspot" => {
-						let text_coordinates: Vec<&str> = split_version[1].split_terminator(',').collect();
-						if text_coordinates.len() != 3 {
-							return Err(error::DmiError::Generic(format!(
-								"Error loading icon: improper hotspot found: {:#?}",
-								split_version
-							)));
-						};
-						hotspot = Some([
-							text_coordinates[0].parse::<u32>()?,
-							text_coordinates[1].parse::<u32>()?,
-							text_coordinates[2].parse::<u32>()?,
-						]);
-					}
-					_ => {
-						unknown_settings = match unknown_settings {
-							None => {
-								let mut new_map = HashMap::new();
-								new_map.insert(split_version[0].to_string(), split_version[1].to_string());
-								Some(new_map)
-							}
-							Some(mut thing) => {
-								thing.insert(split_version[0].to_string(), split_version[1].to_string());
-								Some(thing)
-							}
-						};
-					}
-				};
-			}
-
-			if dirs == None || frames == None {
-				return Err(error::DmiError::Generic(format!(
-					"Error loading icon: state lacks essential settings. dirs: {:#?}. frames: {:#?}.",
-					dirs, frames
-				)));
+					};
+				}
+				_ => {
+					warnings.push(Warning::UnknownSetting {
+						state: Some(name.clone()),
+						setting: key.to_string(),
+					});
+					// Kept in the order they're found, so that saving the icon writes them back the same way.
+					let setting = (key.to_string(), split_version[1].to_string());
+					unknown_settings = match unknown_settings {
+						None => Some(vec![setting]),
+						Some(mut thing) => {
+							thing.push(setting);
+							Some(thing)
+						}
+					};
+					continue;
+				}
 			};
-			let dirs = dirs.unwrap();
-			let frames = frames.unwrap();
+			warn_capitalized(key, warnings);
+		}
 
-			if index + (dirs as u32 * frames) > max_possible_states {
-				return Err(error::DmiError::Generic(format!("Error loading icon: metadata settings exceeded the maximum number of states possible ({}).", max_possible_states)));
-			};
+		let dirs = match dirs {
+			Some(dirs) => dirs,
+			None => {
+				return Err(at_line(
+					state_line_number,
+					error::DmiError::MissingField("dirs"),
+				))
+			}
+		};
+		let frames = match frames {
+			Some(frames) => frames,
+			None => {
+				return Err(at_line(
+					state_line_number,
+					error::DmiError::MissingField("frames"),
+				))
+			}
+		};
 
-			let mut images = vec![];
+		// Those above the next state are left for it, there's no other state to take those above the trailer.
+		if decompressed_text.peek().is_some_and(is_trailer) {
+			comments.extend(decompressed_text.take_comments(state_lines));
+		};
 
-			for _frame in 0..frames {
-				for _dir in 0..dirs {
-					let x = (index % width_in_states) * width;
-					//This operation rounds towards zero, truncating any fractional part of the exact result, essentially a floor() function.
-					let y = (index / width_in_states) * height;
-					images.push(base_image.crop_imm(x, y, width, height));
-					index += 1;
-				}
+		let icon_state = IconState {
+			name,
+			dirs,
+			frames,
+			images: vec![],
+			delay,
+			loop_flag,
+			rewind,
+			movement,
+			hotspots,
+			unknown_settings,
+			comments: if comments.is_empty() {
+				None
+			} else {
+				Some(comments)
+			},
+		};
+
+		match &icon_state.delay {
+			Some(delay) if delay.len() as u32 != icon_state.frames => {
+				warnings.push(Warning::DelayMismatch {
+					state: icon_state.name.clone(),
+					frames: icon_state.frames,
+					delays: delay.len(),
+				})
 			}
+			// Parses just fine, but an animation without a delay is most likely a mistake.
+			None if icon_state.frames > 1 => warnings.push(Warning::MissingDelay {
+				state: icon_state.name.clone(),
+				frames: icon_state.frames,
+			}),
+			_ => (),
+		};
 
-			states.push(IconState {
-				name,
-				dirs,
-				frames,
-				images,
-				delay,
-				loop_flag,
-				rewind,
-				movement,
-				hotspot,
-				unknown_settings,
-			});
-		}
+		Ok(icon_state)
+	}
 
-		Ok(Icon {
-			version: DmiVersion(version),
-			width,
-			height,
-			states,
-		})
+	pub fn save<W: Write>(&self, writter: &mut W) -> Result<usize, error::DmiError> {
+		self.save_with_compression(writter, ztxt::DEFAULT_COMPRESSION_LEVEL)
 	}
 
-	pub fn save<W: Write>(&self, mut writter: &mut W) -> Result<usize, error::DmiError> {
+	/// Same as `save`, compressing the metadata at the given zlib level, from 0 (fastest) to 9 (smallest).
+	pub fn save_with_compression<W: Write>(
+		&self,
+		mut writter: &mut W,
+		compression_level: u8,
+	) -> Result<usize, error::DmiError> {
 		let mut sprites = vec![];
-		let mut signature = format!(
-			"# BEGIN DMI\nversion = {}\n\twidth = {}\n\theight = {}\n",
-			self.version.0, self.width, self.height
-		);
 
 		for icon_state in &self.states {
-			if icon_state.images.len() as u32 != icon_state.dirs as u32 * icon_state.frames {
+			if icon_state.images.len() as u32 != icon_state.image_count() {
 				return Err(error::DmiError::Generic(format!("Error saving Icon: number of images ({}) differs from the stated metadata. Dirs: {}. Frames: {}. Name: \"{}\".", icon_state.images.len(), icon_state.dirs, icon_state.frames, icon_state.name)));
 			};
 
-			signature.push_str(&format!(
-				"state = \"{}\"\n\tdirs = {}\n\tframes = {}\n",
-				icon_state.name, icon_state.dirs, icon_state.frames
-			));
+			for image in &icon_state.images {
+				if image.dimensions() != (self.width, self.height) {
+					return Err(error::DmiError::Generic(format!("Error saving Icon: image dimensions ({:?}) differ from the icon width ({}) / height ({}). Name: \"{}\".", image.dimensions(), self.width, self.height, icon_state.name)));
+				};
+			}
 
 			if icon_state.frames > 1 {
 				match &icon_state.delay {
 					Some(delay) => {
 						if delay.len() as u32 != icon_state.frames {
-							return Err(error::DmiError::Generic(format!("Error saving Icon: number of frames ({}) differs from the delay entry ({:3?}). Name: \"{}\".", icon_state.frames, delay, icon_state.name)))
+							return Err(error::DmiError::Generic(format!("Error saving Icon: number of frames ({}) differs from the delay entry ({:3?}). Name: \"{}\".", icon_state.frames, delay, icon_state.name)));
 						};
-						let delay: Vec<String>= delay.iter().map(|&c| c.to_string()).collect();
-						signature.push_str(&format!("\tdelay = {}\n", delay.join(",")));
-					},
-					None => return Err(error::DmiError::Generic(format!("Error saving Icon: number of frames ({}) larger than one without a delay entry in icon state of name \"{}\".", icon_state.frames, icon_state.name)))
-				};
-				if let Some(flag) = icon_state.loop_flag {
-					signature.push_str(&format!("\tloop = {}\n", flag))
-				}
-				if let Some(flag) = icon_state.rewind {
-					signature.push_str(&format!("\trewind = {}\n", flag))
-				}
-				if let Some(flag) = icon_state.movement {
-					signature.push_str(&format!("\tmovement = {}\n", flag))
-				}
-			};
-
-			if let Some(array) = icon_state.hotspot {
-				signature.push_str(&format!(
-					"\tarray = {},{},{}\n",
-					array[0], array[1], array[2]
-				))
-			};
-
-			match &icon_state.unknown_settings {
-				Some(hashmap) => {
-					for (setting, value) in hashmap.iter() {
-						signature.push_str(&format!("\t{} = {}\n", setting, value));
 					}
-				}
-				None => (),
+					None => return Err(error::DmiError::Generic(format!("Error saving Icon: number of frames ({}) larger than one without a delay entry in icon state of name \"{}\".", icon_state.frames, icon_state.name))),
+				};
 			};
 
 			sprites.extend(icon_state.images.iter());
 		}
 
-		signature.push_str("# END DMI\n");
+		let signature = self.to_string();
 
-		let max_index = (sprites.len() as f64).sqrt().ceil() as u32;
+		let (max_index, sheet_rows) = self.recommended_dimensions();
 		let mut new_png =
-			image::DynamicImage::new_rgba8(max_index * self.width, max_index * self.height);
+			image::DynamicImage::new_rgba8(max_index * self.width, sheet_rows * self.height);
 
 		for image in sprites.iter().enumerate() {
 			let index = image.0 as u32;
@@ -354,50 +708,1298 @@ impl Icon {
 		new_png.write_to(&mut new_dmi, image::ImageOutputFormat::Png)?;
 		let mut new_dmi = RawDmi::load(&new_dmi[..])?;
 
-		let new_ztxt = ztxt::create_ztxt_chunk(signature.as_bytes())?;
+		let new_ztxt = ztxt::create_ztxt_chunk_with_level(signature.as_bytes(), compression_level)?;
 
 		new_dmi.chunk_ztxt = Some(new_ztxt);
 
 		new_dmi.save(&mut writter)
 	}
-}
 
-#[derive(Clone)]
-pub struct IconState {
-	pub name: String,
-	pub dirs: u8,
-	pub frames: u32,
-	pub images: Vec<image::DynamicImage>,
-	pub delay: Option<Vec<f32>>,
-	pub loop_flag: Option<u32>,
-	pub rewind: Option<u32>,
-	pub movement: Option<u32>,
-	pub hotspot: Option<[u32; 3]>,
-	pub unknown_settings: Option<HashMap<String, String>>,
-}
+	/// Writes `original`, a DMI or plain PNG, with its metadata replaced by this icon's.
+	/// Its spritesheet and every other chunk are kept as they were, in their order, so the images of the states
+	/// aren't used. The new metadata takes the place of the old one, or follows the IHDR chunk of a plain PNG.
+	/// Errors if the metadata doesn't fit the original spritesheet.
+	pub fn save_preserving<R: Read, W: Write>(
+		&self,
+		mut original: R,
+		mut writter: &mut W,
+	) -> Result<usize, error::DmiError> {
+		let mut original_bytes = vec![];
+		original.read_to_end(&mut original_bytes)?;
+		let new_ztxt = ztxt::create_ztxt_chunk(self.to_string().as_bytes())?;
 
-impl Default for IconState {
-	fn default() -> Self {
-		IconState {
-			name: String::new(),
-			dirs: 1,
-			frames: 1,
-			images: vec![],
-			delay: None,
-			loop_flag: None,
-			rewind: None,
-			movement: None,
-			hotspot: None,
-			unknown_settings: None,
+		let mut raw_dmi = RawDmi::load(&original_bytes[..])?;
+		raw_dmi.chunk_ztxt = Some(new_ztxt.clone());
+		if let Some(other_chunks) = &mut raw_dmi.other_chunks {
+			other_chunks.retain(|chunk| !is_text_description(chunk));
+		};
+		Icon::load_raw_meta(&raw_dmi, &mut vec![])?;
+
+		let is_description = |chunk: &chunk::RawGenericChunk| {
+			(&chunk.chunk_type == b"zTXt" && chunk.data.starts_with(b"Description\0"))
+				|| is_text_description(chunk)
+		};
+		let chunks = read_chunks(&original_bytes)?;
+		let ztxt_index = match chunks.iter().position(is_description) {
+			Some(index) => index,
+			None => chunks
+				.iter()
+				.position(|chunk| &chunk.chunk_type == b"IHDR")
+				.map_or(0, |index| index + 1),
+		};
+
+		let mut total_bytes_written = writter.write(&raw_dmi.header)?;
+		if total_bytes_written < 8 {
+			return Err(error::DmiError::Generic(format!(
+				"Failed to save DMI. Buffer unable to hold the data, only {} bytes written.",
+				total_bytes_written
+			)));
+		};
+		for (index, chunk) in chunks.iter().enumerate() {
+			if index == ztxt_index {
+				total_bytes_written += new_ztxt.save(&mut writter)?;
+			};
+			if !is_description(chunk) {
+				total_bytes_written += chunk.save(&mut writter)?;
+			};
 		}
+		Ok(total_bytes_written)
 	}
-}
 
-#[derive(Clone)]
-pub struct DmiVersion(String);
+	/// Wraps a single state into an icon of the given sprite dimensions and the default version.
+	/// Nothing is validated, use `IconBuilder` for that.
+	pub fn from_state(width: u32, height: u32, icon_state: IconState) -> Icon {
+		Icon {
+			width,
+			height,
+			states: vec![icon_state],
+			..Default::default()
+		}
+	}
 
-impl Default for DmiVersion {
-	fn default() -> Self {
-		DmiVersion("4.0".to_string())
+	/// Fills the states of `metadata` with the given sprites, one per cell in the order of `cells`.
+	/// Errors if there isn't exactly one sprite per cell, or one of them isn't of the icon's dimensions.
+	pub fn from_frames(
+		mut metadata: Icon,
+		frames: Vec<image::RgbaImage>,
+	) -> Result<Icon, error::DmiError> {
+		if frames.len() as u32 != metadata.total_cells() {
+			return Err(error::DmiError::Generic(format!(
+				"Error building icon: number of frames ({}) differs from the number of cells ({}).",
+				frames.len(),
+				metadata.total_cells()
+			)));
+		};
+		if let Some(frame) = frames
+			.iter()
+			.find(|frame| frame.dimensions() != (metadata.width, metadata.height))
+		{
+			return Err(error::DmiError::Generic(format!(
+				"Error building icon: frame dimensions ({:?}) differ from the icon width ({}) / height ({}).",
+				frame.dimensions(),
+				metadata.width,
+				metadata.height
+			)));
+		};
+		let mut frames = frames.into_iter().map(image::DynamicImage::ImageRgba8);
+		for icon_state in metadata.states.iter_mut() {
+			icon_state.images = frames
+				.by_ref()
+				.take(icon_state.image_count() as usize)
+				.collect();
+		}
+		Ok(metadata)
+	}
+
+	/// Returns the first state with the given name.
+	/// DMIs may hold two states of the same name, one of them a movement state; see `states_named`.
+	pub fn state(&self, name: &str) -> Option<&IconState> {
+		self
+			.states
+			.iter()
+			.find(|icon_state| icon_state.name == name)
+	}
+
+	/// Mutable version of `state`, returning the first state with the given name.
+	pub fn state_mut(&mut self, name: &str) -> Option<&mut IconState> {
+		self
+			.states
+			.iter_mut()
+			.find(|icon_state| icon_state.name == name)
+	}
+
+	/// Returns the first state with the given name that is, or is not, a movement state.
+	pub fn state_for(&self, name: &str, movement: bool) -> Option<&IconState> {
+		self
+			.states
+			.iter()
+			.find(|icon_state| icon_state.name == name && icon_state.is_movement() == movement)
+	}
+
+	/// Returns the sprite of the first state with the given name, facing `dir` on the given frame.
+	/// `None` if there is no such state, it lacks that direction or frame, or its images weren't loaded.
+	pub fn frame(&self, state: &str, dir: Dir, frame: u32) -> Option<image::RgbaImage> {
+		let icon_state = self.state(state)?;
+		if frame >= icon_state.frames {
+			return None;
+		};
+		let directions = icon_state.directions();
+		let dir_index = directions.iter().position(|&state_dir| state_dir == dir)?;
+		let index = frame as usize * icon_state.dirs as usize + dir_index;
+		icon_state.images.get(index).map(|image| image.to_rgba8())
+	}
+
+	/// Iterates over every sprite cell of the spritesheet, in the order BYOND lays them out:
+	/// state by state, each frame in turn, with every direction of a frame one after the other.
+	pub fn cells(&self) -> impl Iterator<Item = Cell<'_>> + '_ {
+		let mut first_index = 0;
+		self.states.iter().flat_map(move |icon_state| {
+			let state_index = first_index;
+			first_index += icon_state.image_count() as usize;
+			let directions = icon_state.directions();
+			// The stride is the stated number of directions, which may exceed the ones with a `Dir`.
+			let stride = icon_state.dirs as usize;
+			(0..icon_state.frames).flat_map(move |frame| {
+				directions
+					.iter()
+					.enumerate()
+					.map(move |(dir_index, &dir)| Cell {
+						state: icon_state,
+						frame,
+						dir,
+						index: state_index + frame as usize * stride + dir_index,
+					})
+			})
+		})
+	}
+
+	/// Checks the header and every state, reporting all the problems found rather than only the first.
+	pub fn validate(&self) -> Result<(), Vec<error::DmiError>> {
+		let mut errors = vec![];
+		if self.width == 0 || self.height == 0 {
+			errors.push(error::DmiError::Generic(format!(
+				"Invalid icon: invalid width ({}) / height ({}) values.",
+				self.width, self.height
+			)));
+		};
+		if !self.version.is_supported() {
+			errors.push(error::DmiError::UnsupportedVersion(
+				self.version.to_string(),
+			));
+		};
+		errors.extend(
+			self
+				.states
+				.iter()
+				.filter_map(|icon_state| icon_state.validate().err()),
+		);
+		match errors.len() {
+			0 => Ok(()),
+			_ => Err(errors),
+		}
+	}
+
+	/// Checks the icon against the given limits on the number of states, the number of cells and the size of the
+	/// spritesheet `save` would write, reporting every limit exceeded.
+	pub fn validate_byond_limits(&self, limits: &ByondLimits) -> Result<(), Vec<error::DmiError>> {
+		let mut errors = vec![];
+		if self.states.len() > limits.max_states {
+			errors.push(error::DmiError::ExceedsLimit {
+				limit: "state count",
+				value: self.states.len() as u64,
+				max: limits.max_states as u64,
+			});
+		};
+		let total_cells = self.total_cells();
+		if total_cells > limits.max_cells {
+			errors.push(error::DmiError::ExceedsLimit {
+				limit: "cell count",
+				value: total_cells as u64,
+				max: limits.max_cells as u64,
+			});
+		};
+		// Counted in 64 bits, the point being to catch sheets too large to write.
+		let (columns, rows) = self.recommended_dimensions();
+		let sheet_width = columns as u64 * self.width as u64;
+		if sheet_width > limits.max_sheet_width as u64 {
+			errors.push(error::DmiError::ExceedsLimit {
+				limit: "spritesheet width",
+				value: sheet_width,
+				max: limits.max_sheet_width as u64,
+			});
+		};
+		let sheet_height = rows as u64 * self.height as u64;
+		if sheet_height > limits.max_sheet_height as u64 {
+			errors.push(error::DmiError::ExceedsLimit {
+				limit: "spritesheet height",
+				value: sheet_height,
+				max: limits.max_sheet_height as u64,
+			});
+		};
+		match errors.len() {
+			0 => Ok(()),
+			_ => Err(errors),
+		}
+	}
+
+	/// Names of the settings this library doesn't know about, found in the header or any of the states.
+	pub fn unknown_keys(&self) -> BTreeSet<String> {
+		self
+			.unknown_settings
+			.iter()
+			.chain(
+				self
+					.states
+					.iter()
+					.filter_map(|icon_state| icon_state.unknown_settings.as_ref()),
+			)
+			.flatten()
+			.map(|(setting, _)| setting.clone())
+			.collect()
+	}
+
+	/// Number of sprite cells the states take up in the spritesheet.
+	pub fn total_cells(&self) -> u32 {
+		self.states.iter().fold(0, |total, icon_state| {
+			total.saturating_add(icon_state.image_count())
+		})
+	}
+
+	/// Roughly square grid, as `(columns, rows)`, fitting every sprite cell. Used when saving the spritesheet.
+	/// Always at least one cell, as a PNG can't be empty.
+	pub fn recommended_dimensions(&self) -> (u32, u32) {
+		let total_cells = self.total_cells().max(1);
+		let columns = (total_cells as f64).sqrt().ceil() as u32;
+		(columns, rows(total_cells, columns))
+	}
+
+	/// Index in the spritesheet of the first state with the given name, facing `dir` on the given frame.
+	/// Matches the `index` of the corresponding `cells` entry.
+	pub fn cell_index(&self, state_name: &str, dir: Dir, frame: u32) -> Option<usize> {
+		let mut first_index = 0;
+		for icon_state in &self.states {
+			if icon_state.name == state_name {
+				if frame >= icon_state.frames {
+					return None;
+				};
+				let directions = icon_state.directions();
+				let dir_index = directions.iter().position(|&state_dir| state_dir == dir)?;
+				return Some(first_index + frame as usize * icon_state.dirs as usize + dir_index);
+			};
+			first_index += icon_state.image_count() as usize;
+		}
+		None
+	}
+
+	/// The state, direction and frame of the sprite at the given spritesheet index, the inverse of `cell_index`.
+	/// `None` as well for the sprites of states with more directions than `Dir` has.
+	pub fn cell_at(&self, index: usize) -> Option<(&IconState, Dir, u32)> {
+		let mut first_index = 0;
+		for icon_state in &self.states {
+			let image_count = icon_state.image_count() as usize;
+			if index < first_index + image_count {
+				let stride = icon_state.dirs as usize;
+				let offset = index - first_index;
+				let frame = (offset / stride) as u32;
+				let dir = *icon_state.directions().get(offset % stride)?;
+				return Some((icon_state, dir, frame));
+			};
+			first_index += image_count;
+		}
+		None
+	}
+
+	/// Every sprite of the spritesheet along with its cell, in the order of `cells`.
+	/// Cells of states whose images weren't loaded are skipped.
+	pub fn all_frames(&self) -> Vec<(Cell<'_>, image::RgbaImage)> {
+		self
+			.cells()
+			.filter_map(|cell| Some((cell, cell.image()?.to_rgba8())))
+			.collect()
+	}
+
+	/// Same as `all_frames`, converting the sprites in parallel.
+	#[cfg(feature = "rayon")]
+	pub fn all_frames_par(&self) -> Vec<(Cell<'_>, image::RgbaImage)> {
+		use rayon::prelude::*;
+		let cells: Vec<Cell<'_>> = self.cells().collect();
+		cells
+			.into_par_iter()
+			.filter_map(|cell| Some((cell, cell.image()?.to_rgba8())))
+			.collect()
+	}
+
+	/// Iterates over every state with the given name, in the order they appear in the DMI.
+	pub fn states_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a IconState> {
+		self
+			.states
+			.iter()
+			.filter(move |icon_state| icon_state.name == name)
+	}
+
+	/// Iterates over the animated states, those with more than one frame.
+	pub fn animated_states(&self) -> impl Iterator<Item = &IconState> {
+		self
+			.states
+			.iter()
+			.filter(|icon_state| icon_state.frames > 1)
+	}
+
+	/// Iterates over the movement states, those with the `movement` flag set.
+	pub fn movement_states(&self) -> impl Iterator<Item = &IconState> {
+		self
+			.states
+			.iter()
+			.filter(|icon_state| icon_state.movement == Some(true))
+	}
+
+	/// Appends the states of another icon of the same sprite dimensions after this one's.
+	/// States sharing a name are kept, as BYOND allows them; use `states_named` to find them.
+	pub fn merge(&mut self, other: Icon) -> Result<(), error::DmiError> {
+		if (other.width, other.height) != (self.width, self.height) {
+			return Err(error::DmiError::Generic(format!(
+				"Error merging icons: width ({}) / height ({}) differ from the icon width ({}) / height ({}).",
+				other.width, other.height, self.width, self.height
+			)));
+		};
+		self.states.extend(other.states);
+		Ok(())
+	}
+
+	/// Appends a state once `IconState::validate` accepts it. Its images, if any, must be exactly `image_count`
+	/// of the icon's dimensions.
+	pub fn add_state(&mut self, icon_state: IconState) -> Result<(), error::DmiError> {
+		icon_state.validate()?;
+		if !icon_state.images.is_empty() {
+			if icon_state.images.len() as u32 != icon_state.image_count() {
+				return Err(error::DmiError::Generic(format!("Error adding icon state: number of images ({}) differs from the stated metadata. Dirs: {}. Frames: {}. Name: \"{}\".", icon_state.images.len(), icon_state.dirs, icon_state.frames, icon_state.name)));
+			};
+			if let Some(image) = icon_state
+				.images
+				.iter()
+				.find(|image| image.dimensions() != (self.width, self.height))
+			{
+				return Err(error::DmiError::Generic(format!("Error adding icon state: image dimensions ({:?}) differ from the icon width ({}) / height ({}). Name: \"{}\".", image.dimensions(), self.width, self.height, icon_state.name)));
+			};
+		};
+		self.states.push(icon_state);
+		Ok(())
+	}
+
+	/// One icon per state, each with a copy of this icon's header and a single state.
+	pub fn split_by_state(&self) -> Vec<Icon> {
+		self
+			.states
+			.iter()
+			.map(|icon_state| self.with_single_state(icon_state))
+			.collect()
+	}
+
+	/// The first state with the given name as an icon of its own, with a copy of this icon's header.
+	/// Saving it packs only that state's cells into the spritesheet.
+	pub fn state_sheet(&self, name: &str) -> Option<Icon> {
+		self
+			.state(name)
+			.map(|icon_state| self.with_single_state(icon_state))
+	}
+
+	fn with_single_state(&self, icon_state: &IconState) -> Icon {
+		Icon {
+			version: self.version,
+			width: self.width,
+			height: self.height,
+			states: vec![icon_state.clone()],
+			unknown_settings: self.unknown_settings.clone(),
+			comments: None,
+		}
+	}
+
+	/// Removes every state with the given name, returning them in the order they were found.
+	pub fn remove_state(&mut self, name: &str) -> Vec<IconState> {
+		let (removed, kept) = std::mem::take(&mut self.states)
+			.into_iter()
+			.partition(|icon_state| icon_state.name == name);
+		self.states = kept;
+		removed
+	}
+
+	/// Removes the first state with the given name, if any.
+	pub fn remove_state_first(&mut self, name: &str) -> Option<IconState> {
+		let index = self
+			.states
+			.iter()
+			.position(|icon_state| icon_state.name == name)?;
+		Some(self.states.remove(index))
+	}
+
+	/// Renames every state called `old` to `new`, returning how many were renamed.
+	pub fn rename_state(&mut self, old: &str, new: &str) -> usize {
+		let mut renamed = 0;
+		for icon_state in self
+			.states
+			.iter_mut()
+			.filter(|icon_state| icon_state.name == old)
+		{
+			icon_state.name = new.to_string();
+			renamed += 1;
+		}
+		renamed
+	}
+
+	/// Moves the states to follow the given order of names, with the states left unlisted kept after them in their current order.
+	/// All the states sharing a listed name are moved together. Errors, leaving the states untouched, if a name is missing or listed twice.
+	pub fn reorder(&mut self, order: &[&str]) -> Result<(), error::DmiError> {
+		for (index, name) in order.iter().enumerate() {
+			if order[..index].contains(name) {
+				return Err(error::DmiError::Generic(format!(
+					"Error reordering icon states: \"{}\" listed more than once.",
+					name
+				)));
+			};
+			if self.state(name).is_none() {
+				return Err(error::DmiError::Generic(format!(
+					"Error reordering icon states: no state named \"{}\".",
+					name
+				)));
+			};
+		}
+		let mut remaining = std::mem::take(&mut self.states);
+		for name in order {
+			let (listed, unlisted): (Vec<IconState>, Vec<IconState>) = remaining
+				.into_iter()
+				.partition(|icon_state| icon_state.name == *name);
+			self.states.extend(listed);
+			remaining = unlisted;
+		}
+		self.states.extend(remaining);
+		Ok(())
+	}
+}
+
+/// Builds an `Icon` of the given sprite dimensions state by state, validating it once built.
+#[derive(Clone, Default)]
+pub struct IconBuilder {
+	icon: Icon,
+}
+
+impl IconBuilder {
+	pub fn new(width: u32, height: u32) -> IconBuilder {
+		IconBuilder {
+			icon: Icon {
+				width,
+				height,
+				..Default::default()
+			},
+		}
+	}
+
+	pub fn version(mut self, version: DmiVersion) -> Self {
+		self.icon.version = version;
+		self
+	}
+
+	pub fn state(mut self, icon_state: IconState) -> Self {
+		self.icon.states.push(icon_state);
+		self
+	}
+
+	/// Returns the built icon, once its dimensions and every state are checked.
+	/// States may hold no images, making for a metadata-only icon, or exactly `image_count` of them.
+	pub fn build(self) -> Result<Icon, error::DmiError> {
+		if self.icon.width == 0 || self.icon.height == 0 {
+			return Err(error::DmiError::Generic(format!(
+				"Error building icon: invalid width ({}) / height ({}) values.",
+				self.icon.width, self.icon.height
+			)));
+		};
+		for icon_state in &self.icon.states {
+			icon_state.validate()?;
+			if !icon_state.images.is_empty() && icon_state.images.len() as u32 != icon_state.image_count()
+			{
+				return Err(error::DmiError::Generic(format!("Error building icon: number of images ({}) differs from the stated metadata. Dirs: {}. Frames: {}. Name: \"{}\".", icon_state.images.len(), icon_state.dirs, icon_state.frames, icon_state.name)));
+			};
+		}
+		Ok(self.icon)
+	}
+}
+
+/// Writes the metadata of the icon in the format stored in the DMI's zTXt chunk, from
+/// `# BEGIN DMI` to `# END DMI`, comments included. Loading the written text back gives an equal icon,
+/// bar NaN delays.
+impl fmt::Display for Icon {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "# BEGIN DMI")?;
+		let mut lines = vec![
+			format!("version = {}", self.version),
+			format!("\twidth = {}", self.width),
+			format!("\theight = {}", self.height),
+		];
+		if let Some(unknown_settings) = &self.unknown_settings {
+			for (setting, value) in unknown_settings {
+				lines.push(format!("\t{} = {}", setting, value));
+			}
+		};
+		write_with_comments(f, &lines, &self.comments)?;
+		for icon_state in &self.states {
+			write!(f, "{}", icon_state)?;
+		}
+		writeln!(f, "# END DMI")
+	}
+}
+
+/// With the `serde` feature, only the metadata of the state is (de)serialized, the images are skipped.
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IconState {
+	pub name: String,
+	pub dirs: u8,
+	pub frames: u32,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	pub images: Vec<image::DynamicImage>,
+	pub delay: Option<Vec<f32>>,
+	pub loop_flag: Option<u32>,
+	pub rewind: Option<bool>,
+	pub movement: Option<bool>,
+	pub hotspots: Option<Vec<Hotspot>>,
+	/// Settings this library doesn't know about, as `(setting, value)` pairs in the order they were found.
+	/// This includes keys like `wait` that some tools emit but BYOND itself doesn't use; frame timing is only read from `delay`.
+	pub unknown_settings: Option<Vec<(String, String)>>,
+	/// `#` comment lines within the state or right above it, as `(position, line)` pairs in order. Each is written
+	/// before the line at that position, 0 being the `state = ` line, or after the last one once past it.
+	/// Settings are written in a fixed order, so comments in hand-ordered states may end up next to other settings.
+	pub comments: Option<Vec<(usize, String)>>,
+}
+
+impl IconState {
+	/// Number of sprites this state takes up in the spritesheet, one per direction of every frame.
+	/// Saturates at `u32::MAX` rather than overflowing, a count no spritesheet can hold; see `checked_image_count`.
+	pub fn image_count(&self) -> u32 {
+		self.frames.saturating_mul(self.dirs as u32)
+	}
+
+	/// Same as `image_count`, but errors if the count overflows.
+	pub fn checked_image_count(&self) -> Result<u32, error::DmiError> {
+		self
+			.frames
+			.checked_mul(self.dirs as u32)
+			.ok_or(error::DmiError::ImageCountOverflow {
+				frames: self.frames,
+				dirs: self.dirs,
+			})
+	}
+
+	/// Whether the state has more than one frame.
+	pub fn is_animated(&self) -> bool {
+		self.frames > 1
+	}
+
+	/// Total duration of one run of the animation, in deciseconds like BYOND delays.
+	/// `None` if the state isn't animated or has no delays.
+	pub fn total_delay(&self) -> Option<f32> {
+		if !self.is_animated() {
+			return None;
+		};
+		self.delay.as_ref().map(|delay| delay.iter().sum())
+	}
+
+	/// The delay of each frame as BYOND plays them, in deciseconds.
+	/// Animated states without delays default to one decisecond per frame, single-frame states have none.
+	pub fn effective_delays(&self) -> Vec<f32> {
+		if !self.is_animated() {
+			return vec![];
+		};
+		match &self.delay {
+			Some(delay) => delay.clone(),
+			None => vec![1.0; self.frames as usize],
+		}
+	}
+
+	/// The delays cut or padded to exactly one per frame, in deciseconds.
+	/// Padding uses BYOND's default of one decisecond rather than cycling through the listed delays.
+	pub fn normalized_delays(&self) -> Vec<f32> {
+		let mut delays = self.delay.clone().unwrap_or_default();
+		delays.resize(self.frames as usize, 1.0);
+		delays
+	}
+
+	/// Whether the animation repeats endlessly. BYOND does so when `loop` is missing or 0.
+	pub fn loops_forever(&self) -> bool {
+		matches!(self.loop_flag, None | Some(0))
+	}
+
+	/// How many times the animation plays before stopping on its last frame, `None` if it loops forever.
+	pub fn loop_count(&self) -> Option<u32> {
+		self.loop_flag.filter(|&count| count > 0)
+	}
+
+	/// Whether this is a movement state, used by BYOND while the atom glides between tiles.
+	pub fn is_movement(&self) -> bool {
+		self.movement == Some(true)
+	}
+
+	/// The directions of each frame of the state, in the order their sprites are stored.
+	/// That is `[South]` for one direction, `[South, North, East, West]` for four, and those followed by
+	/// `[Southeast, Southwest, Northeast, Northwest]` for eight.
+	pub fn directions(&self) -> &'static [Dir] {
+		&DIRECTIONS[..(self.dirs as usize).min(DIRECTIONS.len())]
+	}
+
+	/// Returns the hotspot defined for the given frame / direction index, if any.
+	pub fn hotspot_for(&self, index: u32) -> Option<&Hotspot> {
+		self
+			.hotspots
+			.as_ref()?
+			.iter()
+			.find(|hotspot| hotspot.index == index)
+	}
+
+	/// Checks the consistency of the state's settings.
+	/// Loading is lenient about these, as BYOND itself is. Saving only refuses animated states whose delays
+	/// don't match their frames.
+	pub fn validate(&self) -> Result<(), error::DmiError> {
+		if !matches!(self.dirs, 1 | 4 | 8) {
+			return Err(error::DmiError::InvalidDirs(self.dirs));
+		};
+		if self.frames < 1 {
+			return Err(error::DmiError::Generic(format!(
+				"Invalid icon state: it must have at least one frame. Name: \"{}\".",
+				self.name
+			)));
+		};
+		self.checked_image_count()?;
+		if let Some(delay) = &self.delay {
+			if delay.len() as u32 != self.frames {
+				return Err(error::DmiError::Generic(format!(
+					"Invalid icon state: number of frames ({}) differs from the delay entry ({:3?}). Name: \"{}\".",
+					self.frames, delay, self.name
+				)));
+			};
+			if let Some(value) = delay.iter().find(|value| !value.is_finite()) {
+				return Err(error::DmiError::InvalidValue {
+					setting: "delay",
+					value: value.to_string(),
+					reason: "must be a finite number",
+				});
+			};
+		};
+		if let Some(hotspots) = &self.hotspots {
+			let image_count = self.image_count();
+			if let Some(hotspot) = hotspots.iter().find(|hotspot| hotspot.index >= image_count) {
+				return Err(error::DmiError::HotspotIndex {
+					index: hotspot.index,
+					image_count,
+				});
+			};
+		};
+		Ok(())
+	}
+}
+
+impl Default for IconState {
+	fn default() -> Self {
+		IconState {
+			name: String::new(),
+			dirs: 1,
+			frames: 1,
+			images: vec![],
+			delay: None,
+			loop_flag: None,
+			rewind: None,
+			movement: None,
+			hotspots: None,
+			unknown_settings: None,
+			comments: None,
+		}
+	}
+}
+
+/// Builds an `IconState` one setting at a time, validating it once built.
+#[derive(Clone, Default)]
+pub struct IconStateBuilder {
+	icon_state: IconState,
+}
+
+impl IconStateBuilder {
+	pub fn new() -> IconStateBuilder {
+		Self::default()
+	}
+
+	pub fn name(mut self, name: &str) -> Self {
+		self.icon_state.name = name.to_string();
+		self
+	}
+
+	pub fn dirs(mut self, dirs: u8) -> Self {
+		self.icon_state.dirs = dirs;
+		self
+	}
+
+	pub fn frames(mut self, frames: u32) -> Self {
+		self.icon_state.frames = frames;
+		self
+	}
+
+	pub fn images(mut self, images: Vec<image::DynamicImage>) -> Self {
+		self.icon_state.images = images;
+		self
+	}
+
+	pub fn delay(mut self, delay: Vec<f32>) -> Self {
+		self.icon_state.delay = Some(delay);
+		self
+	}
+
+	pub fn loop_flag(mut self, loop_flag: u32) -> Self {
+		self.icon_state.loop_flag = Some(loop_flag);
+		self
+	}
+
+	pub fn rewind(mut self, rewind: bool) -> Self {
+		self.icon_state.rewind = Some(rewind);
+		self
+	}
+
+	pub fn movement(mut self, movement: bool) -> Self {
+		self.icon_state.movement = Some(movement);
+		self
+	}
+
+	/// Adds a hotspot to the state, on top of any previously added ones.
+	pub fn hotspot(mut self, hotspot: Hotspot) -> Self {
+		self
+			.icon_state
+			.hotspots
+			.get_or_insert_with(Vec::new)
+			.push(hotspot);
+		self
+	}
+
+	/// Adds a setting this library doesn't know about, written after every other one.
+	pub fn unknown_setting(mut self, setting: &str, value: &str) -> Self {
+		self
+			.icon_state
+			.unknown_settings
+			.get_or_insert_with(Vec::new)
+			.push((setting.to_string(), value.to_string()));
+		self
+	}
+
+	/// Returns the built state, as long as `IconState::validate` accepts it.
+	pub fn build(self) -> Result<IconState, error::DmiError> {
+		self.icon_state.validate()?;
+		Ok(self.icon_state)
+	}
+}
+
+/// Writes the `state = "name"` block of the metadata, including any unknown settings.
+/// Writes the known settings in the order BYOND does, whatever order they were set in, followed by the unknown ones.
+impl fmt::Display for IconState {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut lines = vec![
+			format!("state = \"{}\"", escape_state_name(&self.name)),
+			format!("\tdirs = {}", self.dirs),
+			format!("\tframes = {}", self.frames),
+		];
+		if let Some(delay) = &self.delay {
+			let delay: Vec<String> = delay.iter().map(|&c| c.to_string()).collect();
+			lines.push(format!("\tdelay = {}", delay.join(",")));
+		}
+		if let Some(flag) = self.loop_flag {
+			lines.push(format!("\tloop = {}", flag));
+		}
+		if let Some(flag) = self.rewind {
+			lines.push(format!("\trewind = {}", flag as u8));
+		}
+		if let Some(flag) = self.movement {
+			lines.push(format!("\tmovement = {}", flag as u8));
+		}
+		if let Some(hotspots) = &self.hotspots {
+			for hotspot in hotspots {
+				lines.push(format!("\thotspot = {}", hotspot));
+			}
+		}
+		if let Some(unknown_settings) = &self.unknown_settings {
+			for (setting, value) in unknown_settings {
+				lines.push(format!("\t{} = {}", setting, value));
+			}
+		}
+		write_with_comments(f, &lines, &self.comments)
+	}
+}
+
+/// Something the metadata parser tolerated, as reported by `Icon::load_with_warnings`.
+#[derive(Clone, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum Warning {
+	/// A known setting written with capital letters, such as `Width`.
+	CapitalizedKey(String),
+	/// A setting this library doesn't know about, kept in `unknown_settings`. `state` is `None` for header settings.
+	UnknownSetting {
+		state: Option<String>,
+		setting: String,
+	},
+	/// A state whose number of delays differs from its number of frames.
+	DelayMismatch {
+		state: String,
+		frames: u32,
+		delays: usize,
+	},
+	/// An animated state without a delay, which BYOND plays at one tick per frame.
+	MissingDelay { state: String, frames: u32 },
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Warning::CapitalizedKey(key) => write!(f, "Capitalized setting: {}", key),
+			Warning::UnknownSetting {
+				state: None,
+				setting,
+			} => write!(f, "Unknown header setting: {}", setting),
+			Warning::UnknownSetting {
+				state: Some(state),
+				setting,
+			} => write!(f, "Unknown setting in state \"{}\": {}", state, setting),
+			Warning::DelayMismatch {
+				state,
+				frames,
+				delays,
+			} => write!(
+				f,
+				"State \"{}\" has {} frames but {} delays",
+				state, frames, delays
+			),
+			Warning::MissingDelay { state, frames } => {
+				write!(f, "State \"{}\" has {} frames but no delay", state, frames)
+			}
+		}
+	}
+}
+
+/// Adds a warning if the known setting `key` isn't written in lowercase.
+fn warn_capitalized(key: &str, warnings: &mut Vec<Warning>) {
+	if key.bytes().any(|byte| byte.is_ascii_uppercase()) {
+		warnings.push(Warning::CapitalizedKey(key.to_string()));
+	};
+}
+
+/// Whether the line ends the settings before it, by starting a state or being the trailer.
+fn ends_state(line: &str) -> bool {
+	is_trailer(line) || is_state_line(line)
+}
+
+/// Whether the line starts a state. Only the key counts, so settings like `default_state` don't, and
+/// like the other keys it's matched regardless of case.
+fn is_state_line(line: &str) -> bool {
+	line.split_once(" = ").is_some_and(|(key, _)| {
+		key
+			.trim_start_matches(INDENTATION)
+			.eq_ignore_ascii_case("state")
+	})
+}
+
+/// The lines of the metadata text, with trailing whitespace trimmed and blank lines skipped.
+/// `#` comments are set aside until taken by the header or state they were found in.
+struct MetadataLines<'a> {
+	lines: std::str::Lines<'a>,
+	/// Number of lines read so far, including skipped and peeked ones.
+	lines_read: usize,
+	/// Number of the line last returned, the one errors are reported at.
+	line_number: usize,
+	peeked: Option<(usize, &'a str)>,
+	comments: Vec<String>,
+}
+
+impl<'a> MetadataLines<'a> {
+	fn new(text: &'a str) -> Self {
+		MetadataLines {
+			// `lines()` also strips the `\r` of Windows-style `\r\n` line endings.
+			lines: text.lines(),
+			lines_read: 0,
+			line_number: 0,
+			peeked: None,
+			comments: vec![],
+		}
+	}
+
+	/// Reads the next line along with its number, setting aside the comments before it.
+	fn read(&mut self) -> Option<(usize, &'a str)> {
+		if let Some(peeked) = self.peeked.take() {
+			return Some(peeked);
+		};
+		loop {
+			// Hand-edited files may have trailing whitespace, blank lines or `#` comments.
+			let line = self.lines.next()?.trim_end();
+			self.lines_read += 1;
+			if is_comment(line) {
+				self.comments.push(line.to_string());
+			} else if !line.is_empty() {
+				return Some((self.lines_read, line));
+			};
+		}
+	}
+
+	/// Returns the next line, leaving it to be read again.
+	fn peek(&mut self) -> Option<&'a str> {
+		let (line_number, line) = self.read()?;
+		self.peeked = Some((line_number, line));
+		Some(line)
+	}
+
+	/// Returns the next line only if `predicate` holds for it, leaving it to be read again otherwise.
+	fn next_if(&mut self, predicate: impl FnOnce(&str) -> bool) -> Option<&'a str> {
+		if !predicate(self.peek()?) {
+			return None;
+		};
+		self.next()
+	}
+
+	/// Skips whatever is left of a broken state, comments included.
+	fn skip_state(&mut self) {
+		loop {
+			// Comments above a line already read were within the broken state, unlike those above a peeked one.
+			if self.peeked.is_none() {
+				self.comments.clear();
+			};
+			if self.next_if(|line| !ends_state(line)).is_none() {
+				break;
+			};
+		}
+		// Those above the trailer would have been the last ones of the broken state.
+		if self.peek().is_some_and(is_trailer) {
+			self.comments.clear();
+		};
+	}
+
+	/// Takes the comments set aside so far, as `(position, line)` pairs at the given position.
+	fn take_comments(&mut self, position: usize) -> impl Iterator<Item = (usize, String)> + '_ {
+		self
+			.comments
+			.drain(..)
+			.map(move |comment| (position, comment))
+	}
+}
+
+impl<'a> Iterator for MetadataLines<'a> {
+	type Item = &'a str;
+
+	fn next(&mut self) -> Option<&'a str> {
+		let (line_number, line) = self.read()?;
+		self.line_number = line_number;
+		Some(line)
+	}
+}
+
+/// Writes the metadata `lines`, each of the `(position, line)` comments before the line at its position.
+fn write_with_comments(
+	f: &mut fmt::Formatter,
+	lines: &[String],
+	comments: &Option<Vec<(usize, String)>>,
+) -> fmt::Result {
+	let mut comments = comments.iter().flatten().peekable();
+	for (index, line) in lines.iter().enumerate() {
+		while let Some((_, comment)) = comments.next_if(|(position, _)| *position <= index) {
+			writeln!(f, "{}", comment)?;
+		}
+		writeln!(f, "{}", line)?;
+	}
+	for (_, comment) in comments {
+		writeln!(f, "{}", comment)?;
+	}
+	Ok(())
+}
+
+/// Wraps the metadata `error` along with the line it was found at, unless already wrapped.
+fn at_line(line: usize, error: error::DmiError) -> error::DmiError {
+	match error {
+		error::DmiError::Metadata { .. } => error,
+		error => error::DmiError::Metadata {
+			line,
+			source: Box::new(error),
+		},
+	}
+}
+
+/// Whether the metadata line is a `#` comment, other than the `# BEGIN DMI` marker and the trailer.
+fn is_comment(line: &str) -> bool {
+	line.starts_with('#') && line != "# BEGIN DMI" && !is_trailer(line)
+}
+
+/// Whether the metadata line is the `# END DMI` trailer. Anything else on the line is ignored.
+fn is_trailer(line: &str) -> bool {
+	line.contains("# END DMI")
+}
+
+/// Reverts the escaping of `escape_state_name`. Backslashes not followed by `"` or `\` are kept as-is.
+fn unescape_state_name(name: &str) -> String {
+	let mut unescaped = String::with_capacity(name.len());
+	let mut characters = name.chars().peekable();
+	while let Some(character) = characters.next() {
+		if character == '\\' {
+			if let Some(&escaped @ ('"' | '\\')) = characters.peek() {
+				unescaped.push(escaped);
+				characters.next();
+				continue;
+			};
+		};
+		unescaped.push(character);
+	}
+	unescaped
+}
+
+/// Escapes the quotes and backslashes of a state name, so it can be written between quotes.
+fn escape_state_name(name: &str) -> String {
+	name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Number of sprite columns in a spritesheet of the given width.
+/// Errors if the sheet width isn't a multiple of the icon width.
+// `is_multiple_of` would need Rust 1.87.
+#[allow(clippy::manual_is_multiple_of)]
+pub fn columns(sheet_width: u32, icon_width: u32) -> Result<u32, error::DmiError> {
+	if icon_width == 0 || sheet_width % icon_width != 0 {
+		return Err(error::DmiError::Generic(format!(
+			"Invalid spritesheet: its width ({}) is not a multiple of the icon width ({}).",
+			sheet_width, icon_width
+		)));
+	};
+	Ok(sheet_width / icon_width)
+}
+
+/// Number of sprite rows needed to hold `total_cells` sprites in a spritesheet of `columns` columns.
+// `is_multiple_of` would need Rust 1.87.
+#[allow(clippy::manual_is_multiple_of)]
+pub fn rows(total_cells: u32, columns: u32) -> u32 {
+	if columns == 0 {
+		return 0;
+	};
+	// Rounded up, one more row for the remaining cells if any.
+	total_cells / columns + (total_cells % columns != 0) as u32
+}
+
+/// Parses the value of a setting holding a non-negative integer, such as `frames`.
+fn parse_unsigned<T: FromStr<Err = ParseIntError>>(
+	setting: &'static str,
+	value: &str,
+) -> Result<T, error::DmiError> {
+	if value.starts_with('-') {
+		return Err(error::DmiError::InvalidValue {
+			setting,
+			value: value.to_string(),
+			reason: "must be a non-negative integer",
+		});
+	};
+	parse_integer(setting, value)
+}
+
+/// Parses the value of a setting holding a decimal integer, such as the hotspot coordinates.
+fn parse_integer<T: FromStr<Err = ParseIntError>>(
+	setting: &'static str,
+	value: &str,
+) -> Result<T, error::DmiError> {
+	// Rather than a bare parse error, point out values such as `0x20` written in another base.
+	let digits = value
+		.strip_prefix(&['-', '+'][..])
+		.unwrap_or(value)
+		.to_ascii_lowercase();
+	if ["0x", "0o", "0b"]
+		.iter()
+		.any(|prefix| digits.starts_with(prefix))
+	{
+		return Err(error::DmiError::InvalidValue {
+			setting,
+			value: value.to_string(),
+			reason: "only decimal integers are supported",
+		});
+	};
+	Ok(value.parse::<T>()?)
+}
+
+/// Parses a 0/1 setting. Any integer is accepted, nonzero values being `true`.
+fn parse_flag(value: &str) -> Result<bool, error::DmiError> {
+	Ok(value.parse::<i64>()? != 0)
+}
+
+/// A direction an icon state's sprite can face.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Dir {
+	South,
+	North,
+	East,
+	West,
+	Southeast,
+	Southwest,
+	Northeast,
+	Northwest,
+}
+
+/// Every direction, in the order BYOND stores them within a frame.
+/// Single-direction states only hold the first one, four-direction states the first four.
+const DIRECTIONS: [Dir; 8] = [
+	Dir::South,
+	Dir::North,
+	Dir::East,
+	Dir::West,
+	Dir::Southeast,
+	Dir::Southwest,
+	Dir::Northeast,
+	Dir::Northwest,
+];
+
+/// A single sprite of the spritesheet, as yielded by `Icon::cells`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cell<'a> {
+	pub state: &'a IconState,
+	pub frame: u32,
+	pub dir: Dir,
+	/// Position of the sprite in the spritesheet, counting left to right and top to bottom.
+	pub index: usize,
+}
+
+impl<'a> Cell<'a> {
+	/// The sprite of this cell, if the images of its state were loaded.
+	pub fn image(&self) -> Option<&'a image::DynamicImage> {
+		let directions = self.state.directions();
+		let dir_index = directions.iter().position(|&dir| dir == self.dir)?;
+		self
+			.state
+			.images
+			.get(self.frame as usize * self.state.dirs as usize + dir_index)
+	}
+}
+
+/// Limits checked by `Icon::validate_byond_limits`. The defaults are conservative values DreamMaker is known to
+/// handle, rather than exact engine limits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ByondLimits {
+	pub max_states: usize,
+	pub max_cells: u32,
+	/// Maximum spritesheet dimensions, in pixels.
+	pub max_sheet_width: u32,
+	pub max_sheet_height: u32,
+}
+
+impl Default for ByondLimits {
+	fn default() -> Self {
+		ByondLimits {
+			max_states: 512,
+			max_cells: 65536,
+			max_sheet_width: 8192,
+			max_sheet_height: 8192,
+		}
+	}
+}
+
+/// A hotspot of an icon state, as written in `hotspot = x,y,index`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hotspot {
+	/// Coordinates of the hotspot, negative ones pointing outside the icon bounds.
+	pub x: i32,
+	pub y: i32,
+	/// Index of the frame / direction the hotspot applies to.
+	pub index: u32,
+}
+
+impl fmt::Display for Hotspot {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{},{},{}", self.x, self.y, self.index)
+	}
+}
+
+/// The version of the DMI format, written as `major.minor`. Versions are compared by major, then minor number,
+/// so `4.01` equals `4.1`, though a parsed version is written back with the digits it was read with.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DmiVersion {
+	pub major: u8,
+	pub minor: u8,
+	/// Number of digits the minor version was written with, zero-padded, so that `4.01` is written back as is.
+	/// Zero for versions not parsed from text.
+	#[cfg_attr(feature = "serde", serde(default))]
+	minor_digits: u8,
+}
+
+impl DmiVersion {
+	/// A version written as plain `major.minor`, such as `4.0`.
+	pub fn new(major: u8, minor: u8) -> DmiVersion {
+		DmiVersion {
+			major,
+			minor,
+			minor_digits: 0,
+		}
+	}
+
+	/// Whether this library can read DMIs of this version.
+	/// BYOND writes 4.0 files, legacy 3.0 ones are parsed with the same rules as there are no settings
+	/// specific to either of them.
+	pub fn is_supported(&self) -> bool {
+		matches!(self.major, 3 | 4)
+	}
+}
+
+impl Default for DmiVersion {
+	fn default() -> Self {
+		DmiVersion::new(4, 0)
+	}
+}
+
+/// Parses `major.minor` versions. A bare major version, such as `4`, is read as `4.0`.
+impl FromStr for DmiVersion {
+	type Err = error::DmiError;
+
+	fn from_str(text: &str) -> Result<Self, Self::Err> {
+		let invalid_version = || error::DmiError::InvalidValue {
+			setting: "version",
+			value: text.to_string(),
+			reason: "must be a major.minor version number",
+		};
+		let (major, minor) = match text.split_once('.') {
+			Some((major, minor)) => (major, minor),
+			None => (text, "0"),
+		};
+		Ok(DmiVersion {
+			major: parse_unsigned::<u8>("version", major).map_err(|_| invalid_version())?,
+			minor: parse_unsigned::<u8>("version", minor).map_err(|_| invalid_version())?,
+			minor_digits: minor.len().min(usize::from(u8::MAX)) as u8,
+		})
+	}
+}
+
+impl PartialEq for DmiVersion {
+	fn eq(&self, other: &Self) -> bool {
+		(self.major, self.minor) == (other.major, other.minor)
+	}
+}
+
+impl Eq for DmiVersion {}
+
+impl PartialOrd for DmiVersion {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for DmiVersion {
+	fn cmp(&self, other: &Self) -> Ordering {
+		(self.major, self.minor).cmp(&(other.major, other.minor))
+	}
+}
+
+impl Hash for DmiVersion {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		(self.major, self.minor).hash(state);
+	}
+}
+
+impl fmt::Display for DmiVersion {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{}.{:0width$}",
+			self.major,
+			self.minor,
+			width = usize::from(self.minor_digits)
+		)
 	}
 }
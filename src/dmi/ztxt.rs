@@ -8,6 +8,9 @@ use std::io::prelude::*;
 
 pub const ZTXT_TYPE: [u8; 4] = [b'z', b'T', b'X', b't'];
 
+/// The zlib compression level used unless told otherwise, on the usual 0 (fastest) to 9 (smallest) scale.
+pub const DEFAULT_COMPRESSION_LEVEL: u8 = 6;
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct RawZtxtChunk {
 	pub data_length: [u8; 4],
@@ -17,7 +20,15 @@ pub struct RawZtxtChunk {
 }
 
 pub fn create_ztxt_chunk(dmi_signature: &[u8]) -> Result<RawZtxtChunk, error::DmiError> {
-	let compressed_text = encode(dmi_signature);
+	create_ztxt_chunk_with_level(dmi_signature, DEFAULT_COMPRESSION_LEVEL)
+}
+
+/// Same as `create_ztxt_chunk`, compressing the text at the given level, from 0 to 9.
+pub fn create_ztxt_chunk_with_level(
+	dmi_signature: &[u8],
+	compression_level: u8,
+) -> Result<RawZtxtChunk, error::DmiError> {
+	let compressed_text = encode_with_level(dmi_signature, compression_level)?;
 	let data = RawZtxtData {
 		compressed_text,
 		..Default::default()
@@ -289,12 +300,10 @@ impl RawZtxtData {
 	pub fn decode(&self) -> Result<Vec<u8>, error::DmiError> {
 		match inflate::inflate_bytes_zlib(&self.compressed_text) {
 			Ok(decompressed_text) => Ok(decompressed_text),
-			Err(text) => {
-				return Err(error::DmiError::Generic(format!(
-					"Failed to read compressed text. Error: {}",
-					text
-				)))
-			}
+			Err(text) => Err(error::DmiError::Generic(format!(
+				"Failed to read compressed text. Error: {}",
+				text
+			))),
 		}
 	}
 
@@ -317,6 +326,28 @@ pub fn encode(text_to_compress: &[u8]) -> Vec<u8> {
 	deflate::deflate_bytes_zlib(text_to_compress)
 }
 
+/// Compresses the text at the given level, from 0 to 9. The encoder has no stored blocks, so level 0 only
+/// skips the search for repeated strings, and the levels in between map onto its fast, default and high presets.
+pub fn encode_with_level(
+	text_to_compress: &[u8],
+	compression_level: u8,
+) -> Result<Vec<u8>, error::DmiError> {
+	let options = match compression_level {
+		0 => deflate::CompressionOptions::huffman_only(),
+		1..=3 => deflate::CompressionOptions::fast(),
+		4..=6 => deflate::CompressionOptions::default(),
+		7..=9 => deflate::CompressionOptions::high(),
+		_ => {
+			return Err(error::DmiError::InvalidValue {
+				setting: "compression level",
+				value: compression_level.to_string(),
+				reason: "must be between 0 and 9",
+			})
+		}
+	};
+	Ok(deflate::deflate_bytes_zlib_conf(text_to_compress, options))
+}
+
 impl Default for RawZtxtData {
 	fn default() -> Self {
 		RawZtxtData {
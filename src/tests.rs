@@ -1,8 +1,86 @@
+use super::chunk;
+use super::crc;
+use super::dmi::{read_chunks, strip_metadata, RawDmi};
+use super::error;
 use super::icon;
+use super::ztxt;
 use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
 
+/// Wraps a blank RGBA spritesheet of the given size and a zTXt chunk holding `description` into a DMI file.
+fn dmi_with_description(width: u32, height: u32, description: &str) -> Vec<u8> {
+	let mut png = vec![];
+	image::DynamicImage::new_rgba8(width, height)
+		.write_to(&mut png, image::ImageOutputFormat::Png)
+		.expect("Unable to encode png");
+	let mut raw_dmi = RawDmi::load(&png[..]).expect("Unable to load png");
+	raw_dmi.chunk_ztxt =
+		Some(ztxt::create_ztxt_chunk(description.as_bytes()).expect("Unable to create zTXt chunk"));
+	let mut dmi = vec![];
+	raw_dmi.save(&mut dmi).expect("Unable to save dmi");
+	dmi
+}
+
+/// Opens `tests/load_test.dmi`, the lights icon most tests load.
+fn load_test_icon() -> File {
+	File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/load_test.dmi")).expect("No lights dmi")
+}
+
+/// Same as `dmi_with_description`, but with the metadata stored uncompressed in a tEXt chunk.
+fn dmi_with_text_description(width: u32, height: u32, description: &str) -> Vec<u8> {
+	let data = [&b"Description\0"[..], description.as_bytes()].concat();
+	dmi_with_text_chunk(width, height, *b"tEXt", data)
+}
+
+/// Same as `dmi_with_text_description`, but with an iTXt chunk, optionally compressed.
+fn dmi_with_itxt_description(
+	width: u32,
+	height: u32,
+	description: &str,
+	compressed: bool,
+) -> Vec<u8> {
+	let text = match compressed {
+		true => deflate::deflate_bytes_zlib(description.as_bytes()),
+		false => description.as_bytes().to_vec(),
+	};
+	let data = [
+		&b"Description\0"[..],
+		&[compressed as u8, 0],
+		b"en\0",
+		b"Beschreibung\0",
+		&text,
+	]
+	.concat();
+	dmi_with_text_chunk(width, height, *b"iTXt", data)
+}
+
+fn dmi_with_text_chunk(width: u32, height: u32, chunk_type: [u8; 4], data: Vec<u8>) -> Vec<u8> {
+	let mut png = vec![];
+	image::DynamicImage::new_rgba8(width, height)
+		.write_to(&mut png, image::ImageOutputFormat::Png)
+		.expect("Unable to encode png");
+	let mut raw_dmi = RawDmi::load(&png[..]).expect("Unable to load png");
+	raw_dmi.other_chunks = Some(vec![chunk::RawGenericChunk {
+		data_length: (data.len() as u32).to_be_bytes(),
+		chunk_type,
+		crc: crc::calculate_crc(chunk_type.iter().chain(data.iter())).to_be_bytes(),
+		data,
+	}]);
+	let mut dmi = vec![];
+	raw_dmi.save(&mut dmi).expect("Unable to save dmi");
+	dmi
+}
+
+/// Unwraps the error of a failed metadata load into the line it was found at and the underlying error.
+fn metadata_error(result: Result<icon::Icon, error::DmiError>) -> (usize, error::DmiError) {
+	match result {
+		Err(error::DmiError::Metadata { line, source }) => (line, *source),
+		Err(error) => panic!("Unexpected error: {:?}", error),
+		Ok(_) => panic!("Metadata loaded without errors"),
+	}
+}
+
 #[test]
 fn load_dmi() {
 	let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -11,3 +89,2145 @@ fn load_dmi() {
 	let file = File::open(&path).unwrap_or_else(|_| panic!("No lights dmi: {:?}", path));
 	let _lights_icon = icon::Icon::load(&file).expect("Unable to load lights dmi");
 }
+
+#[test]
+fn load_crlf_metadata() {
+	let description = "# BEGIN DMI\r\nversion = 4.0\r\n\twidth = 32\r\n\theight = 32\r\nstate = \"foo\"\r\n\tdirs = 1\r\n\tframes = 1\r\nstate = \"bar\"\r\n\tdirs = 1\r\n\tframes = 1\r\n# END DMI\r\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load(&dmi[..]).expect("Unable to load CRLF dmi");
+	let names: Vec<&str> = icon
+		.states
+		.iter()
+		.map(|state| state.name.as_str())
+		.collect();
+	assert_eq!(names, vec!["foo", "bar"]);
+	assert_eq!(icon.width, 32);
+	assert_eq!(icon.height, 32);
+}
+
+#[test]
+fn metadata_round_trip() {
+	let icon = icon::Icon {
+		width: 32,
+		height: 32,
+		states: vec![
+			icon::IconState {
+				name: "walk".to_string(),
+				dirs: 4,
+				frames: 2,
+				images: vec![image::DynamicImage::new_rgba8(32, 32); 8],
+				delay: Some(vec![1.0, 2.5]),
+				loop_flag: Some(0),
+				rewind: Some(true),
+				hotspots: Some(vec![
+					icon::Hotspot {
+						x: 13,
+						y: 12,
+						index: 1,
+					},
+					icon::Hotspot {
+						x: 2,
+						y: 3,
+						index: 4,
+					},
+				]),
+				unknown_settings: Some(vec![("future".to_string(), "\"lmao\"".to_string())]),
+				..Default::default()
+			},
+			icon::IconState {
+				name: "walk".to_string(),
+				images: vec![image::DynamicImage::new_rgba8(32, 32)],
+				movement: Some(true),
+				..Default::default()
+			},
+		],
+		..Default::default()
+	};
+	let mut dmi = vec![];
+	icon.save(&mut dmi).expect("Unable to save icon");
+	let loaded_icon = icon::Icon::load(&dmi[..]).expect("Unable to load saved icon");
+	assert_eq!(loaded_icon.to_string(), icon.to_string());
+	assert_eq!(
+		icon.to_string(),
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,2.5\n\tloop = 0\n\trewind = 1\n\thotspot = 13,12,1\n\thotspot = 2,3,4\n\tfuture = \"lmao\"\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n\tmovement = 1\n# END DMI\n"
+	);
+
+	let lights_icon = icon::Icon::load(load_test_icon()).expect("Unable to load lights dmi");
+	let mut dmi = vec![];
+	lights_icon
+		.save(&mut dmi)
+		.expect("Unable to save lights dmi");
+	let reloaded_icon = icon::Icon::load(&dmi[..]).expect("Unable to reload lights dmi");
+	assert_eq!(reloaded_icon.to_string(), lights_icon.to_string());
+}
+
+#[test]
+fn load_meta() {
+	let lights_meta =
+		icon::Icon::load_meta(load_test_icon()).expect("Unable to load lights dmi metadata");
+	assert_eq!(lights_meta.width, 160);
+	assert_eq!(lights_meta.height, 160);
+	let names: Vec<&str> = lights_meta
+		.states
+		.iter()
+		.map(|state| state.name.as_str())
+		.collect();
+	assert_eq!(names, vec!["0_1", "1_1"]);
+	assert!(lights_meta
+		.states
+		.iter()
+		.all(|state| state.images.is_empty()));
+
+	let mut png = vec![];
+	image::DynamicImage::new_rgba8(32, 32)
+		.write_to(&mut png, image::ImageOutputFormat::Png)
+		.expect("Unable to encode png");
+	assert!(icon::Icon::load_meta(&png[..]).is_err());
+	assert!(icon::Icon::load_meta(&b"# BEGIN DMI"[..]).is_err());
+}
+
+#[test]
+fn save_validates_image_size() {
+	let mut icon = icon::Icon {
+		width: 32,
+		height: 32,
+		states: vec![icon::IconState {
+			name: "mob".to_string(),
+			dirs: 4,
+			images: vec![image::DynamicImage::new_rgba8(32, 32); 4],
+			..Default::default()
+		}],
+		..Default::default()
+	};
+	let mut dmi = vec![];
+	icon.save(&mut dmi).expect("Unable to save icon");
+	let loaded_meta = icon::Icon::load_meta(&dmi[..]).expect("Unable to load saved metadata");
+	assert_eq!(loaded_meta.to_string(), icon.to_string());
+
+	icon.states[0].images[3] = image::DynamicImage::new_rgba8(32, 16);
+	assert!(icon.save(&mut vec![]).is_err());
+}
+
+#[test]
+fn save_what_loads() {
+	// Saving doesn't run `validate`, only the checks it always did.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"two dirs\"\n\tdirs = 2\n\tframes = 1\nstate = \"extra delay\"\n\tdirs = 1\n\tframes = 1\n\tdelay = 1,2\nstate = \"far hotspot\"\n\tdirs = 1\n\tframes = 1\n\thotspot = 99,-4,5\n# END DMI\n";
+	let dmi = dmi_with_description(64, 64, description);
+	let icon = icon::Icon::load(&dmi[..]).expect("Unable to load icon");
+	assert!(icon
+		.states
+		.iter()
+		.all(|icon_state| icon_state.validate().is_err()));
+
+	let mut saved = vec![];
+	icon.save(&mut saved).expect("Unable to save icon");
+	let reloaded_icon = icon::Icon::load(&saved[..]).expect("Unable to reload icon");
+	assert_eq!(reloaded_icon, icon);
+
+	// Animated states still need a delay for each frame.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"no delay\"\n\tdirs = 1\n\tframes = 2\n# END DMI\n";
+	let mut icon =
+		icon::Icon::load(&dmi_with_description(64, 32, description)[..]).expect("Unable to load icon");
+	assert!(icon.save(&mut vec![]).is_err());
+	icon.states[0].delay = Some(vec![1.0, 2.0, 3.0]);
+	assert!(icon.save(&mut vec![]).is_err());
+	icon.states[0].delay = Some(vec![1.0, 2.0]);
+	assert!(icon.save(&mut vec![]).is_ok());
+}
+
+#[test]
+fn image_count() {
+	let single = icon::IconState::default();
+	assert_eq!(single.image_count(), 1);
+	let walking = icon::IconState {
+		dirs: 4,
+		frames: 2,
+		..Default::default()
+	};
+	assert_eq!(walking.image_count(), 8);
+	let spinning = icon::IconState {
+		dirs: 8,
+		frames: 4,
+		..Default::default()
+	};
+	assert_eq!(spinning.image_count(), 32);
+}
+
+#[test]
+fn validate_state() {
+	let mut state = icon::IconState {
+		frames: 2,
+		delay: Some(vec![1.0, 2.0]),
+		..Default::default()
+	};
+	assert!(state.validate().is_ok());
+	state.delay = Some(vec![1.0, 2.0, 3.0]);
+	assert!(state.validate().is_err());
+	state.delay = None;
+	assert!(state.validate().is_ok());
+	state.frames = 0;
+	assert!(state.validate().is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+	let lights_meta =
+		icon::Icon::load_meta(load_test_icon()).expect("Unable to load lights dmi metadata");
+	let json = serde_json::to_string(&lights_meta).expect("Unable to serialize metadata");
+	let deserialized: icon::Icon =
+		serde_json::from_str(&json).expect("Unable to deserialize metadata");
+	assert_eq!(deserialized.to_string(), lights_meta.to_string());
+}
+
+#[test]
+fn state_lookup() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\n\tmovement = 1\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(96, 96, description);
+	let mut icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+
+	let walk = icon.state("walk").expect("No walk state");
+	assert_eq!(walk.movement, None);
+	assert_eq!(icon.states_named("walk").count(), 2);
+	assert_eq!(
+		icon.states_named("walk").nth(1).map(|state| state.movement),
+		Some(Some(true))
+	);
+	assert!(icon.state("run").is_none());
+
+	icon.state_mut("idle").expect("No idle state").dirs = 4;
+	assert_eq!(icon.state("idle").map(|state| state.dirs), Some(4));
+}
+
+#[test]
+fn state_filters() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\n\tmovement = 1\nstate = \"slide\"\n\tdirs = 1\n\tframes = 1\n\tmovement = 1\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\n\tmovement = 0\n# END DMI\n";
+	let dmi = dmi_with_description(160, 160, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+
+	let animated: Vec<(&str, Option<bool>)> = icon
+		.animated_states()
+		.map(|icon_state| (icon_state.name.as_str(), icon_state.movement))
+		.collect();
+	assert_eq!(animated, vec![("walk", None), ("walk", Some(true))]);
+	let movement: Vec<(&str, u32)> = icon
+		.movement_states()
+		.map(|icon_state| (icon_state.name.as_str(), icon_state.frames))
+		.collect();
+	assert_eq!(movement, vec![("walk", 2), ("slide", 1)]);
+}
+
+#[test]
+fn movement_state_lookup() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\n\tmovement = 1\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(96, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+
+	let moving = icon
+		.state_for("walk", true)
+		.expect("No movement walk state");
+	assert!(moving.is_movement());
+	assert_eq!(moving.dirs, 4);
+	let standing = icon.state_for("walk", false).expect("No still walk state");
+	assert!(!standing.is_movement());
+	assert_eq!(standing.dirs, 1);
+}
+
+#[test]
+fn multiple_hotspots() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"gun\"\n\tdirs = 4\n\tframes = 1\n\thotspot = 13,12,1\n\thotspot = 20,5,3\n# END DMI\n";
+	let dmi = dmi_with_description(64, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let gun = icon.state("gun").expect("No gun state");
+	let first_hotspot = icon::Hotspot {
+		x: 13,
+		y: 12,
+		index: 1,
+	};
+	let second_hotspot = icon::Hotspot {
+		x: 20,
+		y: 5,
+		index: 3,
+	};
+	assert_eq!(gun.hotspots, Some(vec![first_hotspot, second_hotspot]));
+	assert_eq!(gun.hotspot_for(1), Some(&first_hotspot));
+	assert_eq!(gun.hotspot_for(3), Some(&second_hotspot));
+	assert_eq!(gun.hotspot_for(2), None);
+	assert_eq!(first_hotspot.to_string(), "13,12,1");
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"gun\"\n\tdirs = 1\n\tframes = 1\n\thotspot = 13.5,12,1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(icon::Icon::load_meta(&dmi[..]).is_err());
+}
+
+#[test]
+fn specific_errors() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		icon::Icon::load_meta(&dmi[..])
+			.as_ref()
+			.map_err(error::DmiError::kind),
+		Err(error::DmiError::MissingField("dirs"))
+	));
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		icon::Icon::load_meta(&dmi[..])
+			.as_ref()
+			.map_err(error::DmiError::kind),
+		Err(error::DmiError::MissingField("height"))
+	));
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"gun\"\n\tdirs = 1\n\tframes = 1\n\thotspot = 1,2,3,4\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		icon::Icon::load_meta(&dmi[..])
+			.as_ref()
+			.map_err(error::DmiError::kind),
+		Err(error::DmiError::HotspotLength(4))
+	));
+
+	let state = icon::IconState {
+		dirs: 2,
+		..Default::default()
+	};
+	assert!(matches!(
+		state.validate(),
+		Err(error::DmiError::InvalidDirs(2))
+	));
+}
+
+#[test]
+fn error_line_number() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"fine\"\n\tdirs = 1\n\tframes = 1\nstate = \"broken\"\n\tdirs = four\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let error = icon::Icon::load_meta(&dmi[..]).expect_err("Loaded a broken state");
+	assert_eq!(error.line(), Some(9));
+	assert!(matches!(error.kind(), error::DmiError::ParseInt(_)));
+	// The underlying error is left to `source`, rather than repeated in the message.
+	assert_eq!(error.to_string(), "Error on line 9 of the DMI metadata");
+	assert!(std::error::Error::source(&error).is_some());
+
+	// Errors about the whole state point at its first line, rather than whatever follows it.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"no dirs\"\n\tframes = 1\n\n\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let (line, source) = metadata_error(icon::Icon::load_meta(&dmi[..]));
+	assert_eq!(line, 5);
+	assert!(matches!(source, error::DmiError::MissingField("dirs")));
+}
+
+#[test]
+fn space_indentation() {
+	let tab_description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,2\n\tfuture = \"lmao\"\n# END DMI\n";
+	let space_description = "# BEGIN DMI\nversion = 4.0\n    width = 32\n    height = 32\nstate = \"walk\"\n    dirs = 4\n  \tframes = 2\n    delay = 1,2\n    future = \"lmao\"\n# END DMI\n";
+	let tab_dmi = dmi_with_description(96, 96, tab_description);
+	let space_dmi = dmi_with_description(96, 96, space_description);
+	let tab_icon = icon::Icon::load_meta(&tab_dmi[..]).expect("Unable to load tab-indented metadata");
+	let space_icon =
+		icon::Icon::load_meta(&space_dmi[..]).expect("Unable to load space-indented metadata");
+	assert_eq!(space_icon.to_string(), tab_icon.to_string());
+	assert_eq!(space_icon.to_string(), tab_description);
+}
+
+#[test]
+fn legacy_version() {
+	let description = "# BEGIN DMI\nversion = 3.0\n\twidth = 32\n\theight = 32\nstate = \"old\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load 3.0 metadata");
+	assert_eq!(icon.version.to_string(), "3.0");
+	assert_eq!(icon.version.major, 3);
+	assert_eq!(icon.to_string(), description);
+
+	let description = description.replace("version = 3.0", "version = 5.0");
+	let dmi = dmi_with_description(32, 32, &description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(2, error::DmiError::UnsupportedVersion(_))
+	));
+}
+
+#[test]
+fn cell_order() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\nstate = \"spin\"\n\tdirs = 8\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(160, 128, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let cells: Vec<(&str, u32, icon::Dir, usize)> = icon
+		.cells()
+		.map(|cell| (cell.state.name.as_str(), cell.frame, cell.dir, cell.index))
+		.collect();
+	use icon::Dir::*;
+	assert_eq!(
+		cells,
+		vec![
+			("still", 0, South, 0),
+			("walk", 0, South, 1),
+			("walk", 0, North, 2),
+			("walk", 0, East, 3),
+			("walk", 0, West, 4),
+			("walk", 1, South, 5),
+			("walk", 1, North, 6),
+			("walk", 1, East, 7),
+			("walk", 1, West, 8),
+			("spin", 0, South, 9),
+			("spin", 0, North, 10),
+			("spin", 0, East, 11),
+			("spin", 0, West, 12),
+			("spin", 0, Southeast, 13),
+			("spin", 0, Southwest, 14),
+			("spin", 0, Northeast, 15),
+			("spin", 0, Northwest, 16),
+		]
+	);
+}
+
+#[test]
+fn state_directions() {
+	use icon::Dir::*;
+	let mut state = icon::IconState::default();
+	assert_eq!(state.directions(), &[South]);
+	state.dirs = 4;
+	assert_eq!(state.directions(), &[South, North, East, West]);
+	state.dirs = 8;
+	assert_eq!(
+		state.directions(),
+		&[South, North, East, West, Southeast, Southwest, Northeast, Northwest]
+	);
+}
+
+#[test]
+fn build_state() {
+	let built_state = icon::IconStateBuilder::new()
+		.name("walk")
+		.dirs(4)
+		.frames(2)
+		.delay(vec![1.0, 2.0])
+		.loop_flag(0)
+		.hotspot(icon::Hotspot {
+			x: 13,
+			y: 12,
+			index: 1,
+		})
+		.build()
+		.expect("Unable to build state");
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,2\n\tloop = 0\n\thotspot = 13,12,1\n# END DMI\n";
+	let dmi = dmi_with_description(96, 96, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(built_state.to_string(), icon.states[0].to_string());
+
+	assert!(icon::IconStateBuilder::new()
+		.frames(2)
+		.delay(vec![1.0])
+		.build()
+		.is_err());
+}
+
+#[test]
+fn build_icon() {
+	let icon = icon::IconBuilder::new(32, 32)
+		.state(
+			icon::IconStateBuilder::new()
+				.name("idle")
+				.build()
+				.expect("Unable to build idle state"),
+		)
+		.state(
+			icon::IconStateBuilder::new()
+				.name("walk")
+				.dirs(4)
+				.frames(2)
+				.delay(vec![1.0, 1.0])
+				.build()
+				.expect("Unable to build walk state"),
+		)
+		.build()
+		.expect("Unable to build icon");
+	assert_eq!(
+		icon.to_string(),
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\n# END DMI\n"
+	);
+
+	let mismatched_state = icon::IconStateBuilder::new()
+		.dirs(4)
+		.images(vec![image::DynamicImage::new_rgba8(32, 32)])
+		.build()
+		.expect("Unable to build state");
+	assert!(icon::IconBuilder::new(32, 32)
+		.state(mismatched_state)
+		.build()
+		.is_err());
+	assert!(icon::IconBuilder::new(0, 32).build().is_err());
+}
+
+#[test]
+fn icon_from_state() {
+	let icon_state = icon::IconStateBuilder::new()
+		.name("walk")
+		.dirs(4)
+		.frames(2)
+		.delay(vec![1.0, 2.0])
+		.build()
+		.expect("Unable to build walk state");
+	let icon = icon::Icon::from_state(32, 32, icon_state.clone());
+	assert_eq!(icon.version, icon::DmiVersion::default());
+	assert_eq!(icon.states, vec![icon_state]);
+	assert_eq!(
+		icon.to_string(),
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,2\n# END DMI\n"
+	);
+}
+
+#[test]
+fn canonical_setting_order() {
+	let icon_state = icon::IconStateBuilder::new()
+		.unknown_setting("future", "1")
+		.hotspot(icon::Hotspot {
+			x: 1,
+			y: 2,
+			index: 0,
+		})
+		.movement(true)
+		.rewind(false)
+		.loop_flag(3)
+		.delay(vec![1.0, 2.0])
+		.frames(2)
+		.dirs(4)
+		.name("walk")
+		.build()
+		.expect("Unable to build walk state");
+	assert_eq!(
+		icon_state.to_string(),
+		"state = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,2\n\tloop = 3\n\trewind = 0\n\tmovement = 1\n\thotspot = 1,2,0\n\tfuture = 1\n"
+	);
+}
+
+#[test]
+fn unknown_settings_order() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n\tzeta = 1\n\talpha = \"two\"\n\tmiddle = 3\n\tbeta = 4\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let keys: Vec<&str> = icon.states[0]
+		.unknown_settings
+		.iter()
+		.flatten()
+		.map(|(setting, _)| setting.as_str())
+		.collect();
+	assert_eq!(keys, vec!["zeta", "alpha", "middle", "beta"]);
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn unknown_value_round_trip() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n\tfuture = \"lmao\"\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n\tfuture = \"lmao\"\n\tcount = 3\n\tscale = 0.5\n\toffsets = 1,-2,3.5\n\tnames = \"a\",\"b\"\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	// Values are kept as written, quotes included, so that they're written back the same way.
+	assert_eq!(
+		icon.states[0].unknown_settings,
+		Some(vec![
+			("future".to_string(), "\"lmao\"".to_string()),
+			("count".to_string(), "3".to_string()),
+			("scale".to_string(), "0.5".to_string()),
+			("offsets".to_string(), "1,-2,3.5".to_string()),
+			("names".to_string(), "\"a\",\"b\"".to_string()),
+		])
+	);
+	assert_eq!(icon.to_string(), description);
+
+	let mut saved = vec![];
+	icon
+		.save_preserving(&dmi[..], &mut saved)
+		.expect("Unable to save icon");
+	let saved_icon = icon::Icon::load_meta(&saved[..]).expect("Unable to reload metadata");
+	assert_eq!(saved_icon.to_string(), description);
+}
+
+#[test]
+fn mis_sized_sheet() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\n# END DMI\n";
+	assert!(icon::Icon::load(&dmi_with_description(64, 64, description)[..]).is_ok());
+	// Neither dimension of the sheet is a multiple of the sprite's.
+	assert!(icon::Icon::load(&dmi_with_description(70, 64, description)[..]).is_err());
+	assert!(icon::Icon::load(&dmi_with_description(64, 70, description)[..]).is_err());
+	// Too few cells for the four directions.
+	let (_, error) = metadata_error(icon::Icon::load(
+		&dmi_with_description(96, 32, description)[..],
+	));
+	assert!(error
+		.to_string()
+		.contains("maximum number of states possible (3)"));
+}
+
+#[test]
+fn non_decimal_integers() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 0x20\n\theight = 32\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let (line, error) = metadata_error(icon::Icon::load_meta(&dmi[..]));
+	assert_eq!(line, 3);
+	assert!(matches!(
+		error,
+		error::DmiError::InvalidValue {
+			setting: "width",
+			reason: "only decimal integers are supported",
+			..
+		}
+	));
+	assert_eq!(
+		error.to_string(),
+		"Invalid width value \"0x20\": only decimal integers are supported"
+	);
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 0B1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(
+			6,
+			error::DmiError::InvalidValue {
+				setting: "dirs",
+				reason: "only decimal integers are supported",
+				..
+			}
+		)
+	));
+
+	// A leading `+` is still fine, as it always was.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = +32\n\theight = 32\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.width, 32);
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = +0x20\n\theight = 32\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(
+			3,
+			error::DmiError::InvalidValue {
+				setting: "width",
+				reason: "only decimal integers are supported",
+				..
+			}
+		)
+	));
+}
+
+#[test]
+fn float_syntax() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"exponent\"\n\tdirs = 1\n\tframes = 2\n\tdelay = 1e2,3.5e-1\nstate = \"negative\"\n\tdirs = 1\n\tframes = 2\n\tdelay = -1.5,+2\n# END DMI\n";
+	let dmi = dmi_with_description(64, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.version.to_string(), "4.0");
+	assert_eq!(icon.states[0].delay, Some(vec![100.0, 0.35]));
+	assert_eq!(icon.states[1].delay, Some(vec![-1.5, 2.0]));
+}
+
+#[test]
+fn invalid_frames_and_dirs() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = -1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(
+			7,
+			error::DmiError::InvalidValue {
+				setting: "frames",
+				..
+			}
+		)
+	));
+
+	let description = description.replace("frames = -1", "frames = 0");
+	let dmi = dmi_with_description(32, 32, &description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(
+			7,
+			error::DmiError::InvalidValue {
+				setting: "frames",
+				..
+			}
+		)
+	));
+
+	let description = description
+		.replace("frames = 0", "frames = 1")
+		.replace("dirs = 1", "dirs = -4");
+	let dmi = dmi_with_description(32, 32, &description);
+	let (line, source) = metadata_error(icon::Icon::load_meta(&dmi[..]));
+	assert_eq!(line, 6);
+	assert_eq!(
+		source.to_string(),
+		"Invalid dirs value \"-4\": must be a non-negative integer"
+	);
+}
+
+#[test]
+fn total_delay() {
+	let animated = icon::IconState {
+		frames: 4,
+		delay: Some(vec![1.0, 2.0, 5.4, 3.0]),
+		..Default::default()
+	};
+	assert!(animated.is_animated());
+	assert!((animated.total_delay().expect("No total delay") - 11.4).abs() < 1e-5);
+
+	let still = icon::IconState {
+		delay: Some(vec![2.0]),
+		..Default::default()
+	};
+	assert!(!still.is_animated());
+	assert_eq!(still.total_delay(), None);
+}
+
+#[test]
+fn effective_delays() {
+	let mut state = icon::IconState {
+		frames: 3,
+		delay: Some(vec![1.0, 2.0, 3.0]),
+		..Default::default()
+	};
+	assert_eq!(state.effective_delays(), vec![1.0, 2.0, 3.0]);
+	state.delay = None;
+	assert_eq!(state.effective_delays(), vec![1.0, 1.0, 1.0]);
+	state.frames = 1;
+	assert!(state.effective_delays().is_empty());
+}
+
+#[test]
+fn clone_equality() {
+	let lights_icon = icon::Icon::load(load_test_icon()).expect("Unable to load lights dmi");
+	let mut cloned_icon = lights_icon.clone();
+	assert_eq!(cloned_icon, lights_icon);
+	cloned_icon.states[0].name = "renamed".to_string();
+	assert_ne!(cloned_icon, lights_icon);
+}
+
+#[test]
+fn byte_order_mark() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let bom_dmi = dmi_with_description(32, 32, &format!("\u{FEFF}{}", description));
+	let bom_icon = icon::Icon::load_meta(&bom_dmi[..]).expect("Unable to load BOM metadata");
+	assert_eq!(bom_icon, icon);
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn blank_lines_and_trailing_whitespace() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4  \n\tframes = 1\t\n\nstate = \"idle\"\n\tdirs = 1\n\t\n\tframes = 1\n\n# END DMI\n";
+	let dmi = dmi_with_description(96, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.to_string(),
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n"
+	);
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n\nstate = \"walk\"\n\tdirs = four\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert_eq!(metadata_error(icon::Icon::load_meta(&dmi[..])).0, 7);
+}
+
+#[test]
+fn extract_frame() {
+	// Every sprite is filled with a color encoding its position within the state.
+	let sprite = |red: u8, green: u8| {
+		image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+			32,
+			32,
+			image::Rgba([red, green, 0, 255]),
+		))
+	};
+	let icon = icon::Icon {
+		width: 32,
+		height: 32,
+		states: vec![
+			icon::IconState {
+				name: "still".to_string(),
+				images: vec![sprite(255, 255)],
+				..Default::default()
+			},
+			icon::IconState {
+				name: "walk".to_string(),
+				dirs: 4,
+				frames: 2,
+				delay: Some(vec![1.0, 1.0]),
+				images: (0..2)
+					.flat_map(|frame| (0..4).map(move |dir| sprite(frame, dir)))
+					.collect(),
+				..Default::default()
+			},
+			// More directions than `Dir` has, each frame still takes all 16 sprites.
+			icon::IconState {
+				name: "wide".to_string(),
+				dirs: 16,
+				frames: 2,
+				delay: Some(vec![1.0, 1.0]),
+				images: (0..2)
+					.flat_map(|frame| (0..16).map(move |dir| sprite(frame, dir)))
+					.collect(),
+				..Default::default()
+			},
+		],
+		..Default::default()
+	};
+	let mut dmi = vec![];
+	icon.save(&mut dmi).expect("Unable to save icon");
+	let loaded_icon = icon::Icon::load(&dmi[..]).expect("Unable to load icon");
+
+	let frame = loaded_icon
+		.frame("walk", icon::Dir::West, 1)
+		.expect("No west frame");
+	assert_eq!(frame.dimensions(), (32, 32));
+	assert_eq!(*frame.get_pixel(16, 16), image::Rgba([1, 3, 0, 255]));
+	let frame = loaded_icon
+		.frame("still", icon::Dir::South, 0)
+		.expect("No still frame");
+	assert_eq!(*frame.get_pixel(0, 31), image::Rgba([255, 255, 0, 255]));
+	let frame = loaded_icon
+		.frame("wide", icon::Dir::North, 1)
+		.expect("No wide frame");
+	assert_eq!(*frame.get_pixel(16, 16), image::Rgba([1, 1, 0, 255]));
+
+	assert!(loaded_icon.frame("still", icon::Dir::North, 0).is_none());
+	assert!(loaded_icon.frame("walk", icon::Dir::South, 2).is_none());
+	assert!(loaded_icon.frame("run", icon::Dir::South, 0).is_none());
+}
+
+#[test]
+fn sheet_grid() {
+	assert_eq!(icon::columns(96, 32).expect("Invalid columns"), 3);
+	assert!(icon::columns(100, 32).is_err());
+	assert!(icon::columns(96, 0).is_err());
+	assert_eq!(icon::rows(7, 3), 3);
+	assert_eq!(icon::rows(9, 3), 3);
+	assert_eq!(icon::rows(0, 3), 0);
+}
+
+#[test]
+fn load_states_callback() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\nstate = \"spin\"\n\tdirs = 8\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(160, 128, description);
+	let full_icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+
+	let mut streamed_states = vec![];
+	let header = icon::Icon::load_states(&dmi[..], |icon_state| streamed_states.push(icon_state))
+		.expect("Unable to stream states");
+	assert!(header.states.is_empty());
+	assert_eq!((header.width, header.height), (32, 32));
+	assert_eq!(streamed_states.len(), 3);
+	assert_eq!(streamed_states, full_icon.states);
+}
+
+#[test]
+fn custom_setting_keys() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n\tcustom_key2 = 5\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.states[0].unknown_settings,
+		Some(vec![("custom_key2".to_string(), "5".to_string())])
+	);
+}
+
+#[test]
+fn escaped_state_names() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"say \\\"hi\\\" \\\\o/\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].name, "say \"hi\" \\o/");
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn flag_settings() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n\trewind = 0\n\tmovement = 1\nstate = \"run\"\n\tdirs = 1\n\tframes = 1\n\tmovement = 2\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].rewind, Some(false));
+	assert_eq!(icon.states[0].movement, Some(true));
+	assert_eq!(icon.states[1].rewind, None);
+	assert_eq!(icon.states[1].movement, Some(true));
+	assert!(icon.to_string().contains("\tmovement = 1\n# END DMI"));
+}
+
+#[test]
+fn hotspot_index_range() {
+	let hotspot = |index| icon::Hotspot { x: 1, y: 2, index };
+	let builder = || icon::IconStateBuilder::new().name("gun").dirs(4).frames(2);
+	assert!(builder().hotspot(hotspot(7)).build().is_ok());
+	assert!(matches!(
+		builder().hotspot(hotspot(0)).hotspot(hotspot(8)).build(),
+		Err(error::DmiError::HotspotIndex {
+			index: 8,
+			image_count: 8
+		})
+	));
+}
+
+#[test]
+fn unknown_header_settings() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n\tpalette = 16\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.unknown_settings,
+		Some(vec![("palette".to_string(), "16".to_string())])
+	);
+	assert_eq!(icon.states.len(), 1);
+	assert_eq!(icon.to_string(), description);
+
+	// Only a `state` key starts a state, not any key ending in it.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n\tdefault_state = \"a\"\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.unknown_settings,
+		Some(vec![("default_state".to_string(), "\"a\"".to_string())])
+	);
+	assert_eq!(icon.states.len(), 1);
+	assert_eq!(icon.to_string(), description);
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n\tversion = 4.0\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let (line, error) = metadata_error(icon::Icon::load_meta(&dmi[..]));
+	assert_eq!(line, 8);
+	assert!(matches!(error, error::DmiError::Generic(_)));
+}
+
+#[test]
+fn cell_index_round_trip() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\nstate = \"spin\"\n\tdirs = 8\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(160, 128, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	for cell in icon.cells() {
+		assert_eq!(
+			icon.cell_index(&cell.state.name, cell.dir, cell.frame),
+			Some(cell.index)
+		);
+		let (icon_state, dir, frame) = icon.cell_at(cell.index).expect("No cell at index");
+		assert_eq!(
+			(icon_state.name.as_str(), dir, frame),
+			(cell.state.name.as_str(), cell.dir, cell.frame)
+		);
+	}
+	assert_eq!(icon.cell_index("walk", icon::Dir::West, 1), Some(8));
+	assert_eq!(icon.cell_index("walk", icon::Dir::West, 2), None);
+	assert_eq!(icon.cell_index("still", icon::Dir::North, 0), None);
+	assert_eq!(icon.cell_index("run", icon::Dir::South, 0), None);
+	assert!(icon.cell_at(17).is_none());
+}
+
+#[test]
+fn cells_past_the_known_directions() {
+	// BYOND loads states of 16 directions, whose sprites still take room even without a `Dir` for each.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"wide\"\n\tdirs = 16\n\tframes = 2\n\tdelay = 1,1\nstate = \"after\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(192, 192, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.cell_index("wide", icon::Dir::North, 1), Some(17));
+	assert_eq!(icon.cell_index("after", icon::Dir::South, 0), Some(32));
+	assert!(icon.cell_at(10).is_none());
+	let (icon_state, dir, frame) = icon.cell_at(32).expect("No cell at index");
+	assert_eq!(
+		(icon_state.name.as_str(), dir, frame),
+		("after", icon::Dir::South, 0)
+	);
+	assert_eq!(icon.cells().last().map(|cell| cell.index), Some(32));
+}
+
+#[test]
+fn spaced_delay_list() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"blink\"\n\tdirs = 1\n\tframes = 4\n\tdelay = 1, 2, 5.4, 3\n# END DMI\n";
+	let dmi = dmi_with_description(64, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].delay, Some(vec![1.0, 2.0, 5.4, 3.0]));
+}
+
+#[test]
+fn duplicate_settings() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n\tdirs = 4\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(8, error::DmiError::DuplicateField("dirs"))
+	));
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n\tDelay = 1\n\tdelay = 2\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(9, error::DmiError::DuplicateField("delay"))
+	));
+
+	// Each state may set its own.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\nstate = \"ghost\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	assert!(icon::Icon::load_meta(&dmi[..]).is_ok());
+}
+
+#[test]
+fn hotspot_lengths() {
+	let hotspot = |value: &str| {
+		let description = format!("# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"gun\"\n\tdirs = 1\n\tframes = 2\n\tdelay = 1,1\n\thotspot = {}\n# END DMI\n", value);
+		let dmi = dmi_with_description(64, 32, &description);
+		icon::Icon::load_meta(&dmi[..]).map(|icon| icon.states[0].hotspots.clone())
+	};
+	assert_eq!(
+		hotspot("12,13").expect("Unable to load two-value hotspot"),
+		Some(vec![icon::Hotspot {
+			x: 12,
+			y: 13,
+			index: 0
+		}])
+	);
+	assert_eq!(
+		hotspot("12,13,1").expect("Unable to load three-value hotspot"),
+		Some(vec![icon::Hotspot {
+			x: 12,
+			y: 13,
+			index: 1
+		}])
+	);
+	// Spaces around the commas, as hand-edited files may have.
+	assert_eq!(
+		hotspot("1, 2, 0").expect("Unable to load spaced hotspot"),
+		Some(vec![icon::Hotspot {
+			x: 1,
+			y: 2,
+			index: 0
+		}])
+	);
+	for value in &["0x10,2", "1,-0x2", "1,2,0b1", "+0x1,2"] {
+		assert!(matches!(
+			metadata_error(hotspot(value).map(|_| icon::Icon::default())),
+			(
+				_,
+				error::DmiError::InvalidValue {
+					setting: "hotspot",
+					..
+				}
+			)
+		));
+	}
+	assert!(matches!(
+		metadata_error(hotspot("12").map(|_| icon::Icon::default())),
+		(_, error::DmiError::HotspotLength(1))
+	));
+	assert!(matches!(
+		metadata_error(hotspot("12,13,1,0").map(|_| icon::Icon::default())),
+		(_, error::DmiError::HotspotLength(4))
+	));
+}
+
+#[test]
+fn negative_hotspot() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"gun\"\n\tdirs = 1\n\tframes = 1\n\thotspot = -1,2,0\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.states[0].hotspots,
+		Some(vec![icon::Hotspot {
+			x: -1,
+			y: 2,
+			index: 0
+		}])
+	);
+	assert_eq!(icon.to_string(), description);
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"gun\"\n\tdirs = 1\n\tframes = 1\n\thotspot = 1,2,-1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(
+			8,
+			error::DmiError::InvalidValue {
+				setting: "hotspot",
+				..
+			}
+		)
+	));
+}
+
+#[test]
+fn load_meta_lenient() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"broken\"\n\tdirs = 1\n\tframes = one\n\tdelay = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\nstate = \"unnamed\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 64, description);
+	assert!(icon::Icon::load_meta(&dmi[..]).is_err());
+
+	let (icon, skipped) = icon::Icon::load_meta_lenient(&dmi[..]).expect("Unable to load metadata");
+	let names: Vec<&str> = icon
+		.states
+		.iter()
+		.map(|icon_state| icon_state.name.as_str())
+		.collect();
+	assert_eq!(names, vec!["walk"]);
+	assert_eq!(icon.states[0].dirs, 4);
+	assert_eq!(skipped.len(), 2);
+	assert!(matches!(
+		skipped[0],
+		error::DmiError::Metadata {
+			line: 7,
+			ref source
+		} if matches!(**source, error::DmiError::ParseInt(_))
+	));
+	assert!(matches!(
+		skipped[1],
+		error::DmiError::Metadata { line: 12, .. }
+	));
+
+	let (text_icon, _) =
+		icon::Icon::load_meta_lenient(description.as_bytes()).expect("Unable to load metadata text");
+	assert_eq!(text_icon, icon);
+
+	// Comments of the skipped states are left out along with them.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n# above broken\nstate = \"broken\"\n\tdirs = 1\n# within broken\n\tframes = one\n# also within broken\n\tdelay = 1\n# above walk\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\nstate = \"unnamed\n# within unnamed\n\tdirs = 1\n\tframes = 1\n# after unnamed\n# END DMI\n";
+	let (icon, skipped) =
+		icon::Icon::load_meta_lenient(description.as_bytes()).expect("Unable to load metadata text");
+	let lines: Vec<Option<usize>> = skipped.iter().map(error::DmiError::line).collect();
+	assert_eq!(lines, vec![Some(9), Some(16)]);
+	assert_eq!(
+		icon.to_string(),
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n# above walk\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\n# END DMI\n"
+	);
+
+	// The header must still parse.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(icon::Icon::load_meta_lenient(&dmi[..]).is_err());
+}
+
+#[test]
+fn single_delay() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"blink\"\n\tdirs = 1\n\tframes = 1\n\tdelay = 2\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].delay, Some(vec![2.0]));
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn total_cells() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 2\n\tdelay = 1,1\nstate = \"spin\"\n\tdirs = 8\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(160, 128, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.total_cells(), 17);
+	assert_eq!(icon.recommended_dimensions(), (5, 4));
+
+	let icon = icon::Icon::load(load_test_icon()).expect("Unable to load dmi");
+	assert_eq!(icon.total_cells(), 2);
+	assert_eq!(icon.recommended_dimensions(), (2, 1));
+}
+
+#[test]
+fn no_states() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert!(icon.states.is_empty());
+	assert_eq!(icon.to_string(), description);
+	assert_eq!(icon.recommended_dimensions(), (1, 1));
+
+	let mut saved = vec![];
+	icon.save(&mut saved).expect("Unable to save icon");
+	let reloaded_icon = icon::Icon::load(&saved[..]).expect("Unable to reload icon");
+	assert_eq!(reloaded_icon, icon);
+}
+
+#[test]
+fn capitalized_keys() {
+	let description = "# BEGIN DMI\nVersion = 4.0\n\tWidth = 32\n\tHEIGHT = 32\nstate = \"mob\"\n\tDIRS = 4\n\tFrames = 1\n\tCustom = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!((icon.width, icon.height), (32, 32));
+	assert_eq!(icon.states[0].dirs, 4);
+	assert_eq!(icon.states[0].frames, 1);
+	assert_eq!(
+		icon.states[0].unknown_settings,
+		Some(vec![("Custom".to_string(), "1".to_string())])
+	);
+}
+
+#[test]
+fn wait_is_unknown() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"blink\"\n\tdirs = 1\n\tframes = 2\n\twait = 3\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].delay, None);
+	assert_eq!(
+		icon.states[0].unknown_settings,
+		Some(vec![("wait".to_string(), "3".to_string())])
+	);
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn merge_icons() {
+	let state = |name: &str| {
+		icon::IconStateBuilder::new()
+			.name(name)
+			.build()
+			.expect("Invalid state")
+	};
+	let mut icon = icon::IconBuilder::new(32, 32)
+		.state(state("walk"))
+		.build()
+		.expect("Invalid icon");
+	let other_icon = icon::IconBuilder::new(32, 32)
+		.state(state("run"))
+		.state(state("walk"))
+		.build()
+		.expect("Invalid icon");
+	icon.merge(other_icon).expect("Unable to merge icons");
+	let names: Vec<&str> = icon
+		.states
+		.iter()
+		.map(|icon_state| icon_state.name.as_str())
+		.collect();
+	assert_eq!(names, vec!["walk", "run", "walk"]);
+	assert_eq!(icon.states_named("walk").count(), 2);
+
+	let small_icon = icon::IconBuilder::new(16, 16)
+		.state(state("tiny"))
+		.build()
+		.expect("Invalid icon");
+	assert!(icon.merge(small_icon).is_err());
+	assert_eq!(icon.states.len(), 3);
+}
+
+#[test]
+fn remove_states() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n\tmovement = 1\n# END DMI\n";
+	let dmi = dmi_with_description(96, 96, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+
+	let mut pruned_icon = icon.clone();
+	let removed = pruned_icon
+		.remove_state_first("walk")
+		.expect("No walk state");
+	assert_eq!(removed.dirs, 4);
+	assert_eq!(pruned_icon.states.len(), 2);
+	assert!(pruned_icon
+		.state("walk")
+		.expect("No walk state")
+		.is_movement());
+	assert!(pruned_icon.remove_state_first("run").is_none());
+
+	let mut pruned_icon = icon.clone();
+	let removed = pruned_icon.remove_state("walk");
+	assert_eq!(
+		removed,
+		vec![icon.states[0].clone(), icon.states[2].clone()]
+	);
+	assert_eq!(pruned_icon.states, vec![icon.states[1].clone()]);
+	assert!(pruned_icon.remove_state("walk").is_empty());
+}
+
+#[test]
+fn rename_state() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n\twalk = 1\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let mut icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.rename_state("walk", "run"), 1);
+	assert_eq!(icon.rename_state("fly", "swim"), 0);
+	assert_eq!(
+		icon.to_string(),
+		description.replace("state = \"walk\"", "state = \"run\"")
+	);
+}
+
+#[test]
+fn reorder_states() {
+	let state = |name: &str| {
+		icon::IconStateBuilder::new()
+			.name(name)
+			.build()
+			.expect("Invalid state")
+	};
+	let mut icon = icon::IconBuilder::new(32, 32)
+		.state(state("walk"))
+		.state(state("idle"))
+		.state(state("run"))
+		.state(state("walk"))
+		.state(state("dead"))
+		.build()
+		.expect("Invalid icon");
+	let names = |icon: &icon::Icon| -> Vec<String> {
+		icon
+			.states
+			.iter()
+			.map(|icon_state| icon_state.name.clone())
+			.collect()
+	};
+
+	icon
+		.reorder(&["run", "walk"])
+		.expect("Unable to reorder states");
+	assert_eq!(names(&icon), vec!["run", "walk", "walk", "idle", "dead"]);
+
+	assert!(icon.reorder(&["dead", "fly"]).is_err());
+	assert!(icon.reorder(&["dead", "run", "dead"]).is_err());
+	assert_eq!(names(&icon), vec!["run", "walk", "walk", "idle", "dead"]);
+}
+
+#[test]
+fn leading_dot_delays() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"blink\"\n\tdirs = 1\n\tframes = 2\n\tdelay = .5,1.5\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].delay, Some(vec![0.5, 1.5]));
+}
+
+#[test]
+fn integer_version() {
+	let description = "# BEGIN DMI\nversion = 4\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.version.to_string(), "4.0");
+	assert!(icon.to_string().starts_with("# BEGIN DMI\nversion = 4.0\n"));
+
+	let description = description.replace("version = 4", "version = 5");
+	let dmi = dmi_with_description(32, 32, &description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(2, error::DmiError::UnsupportedVersion(_))
+	));
+}
+
+#[test]
+fn save_preserving_chunks() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let mut raw_dmi =
+		RawDmi::load(&dmi_with_description(32, 32, description)[..]).expect("Unable to load dmi");
+	// Physical pixel dimensions, an ancillary chunk this library doesn't know about.
+	let phys_data = vec![0, 0, 11, 19, 0, 0, 11, 19, 1];
+	let phys_chunk = chunk::RawGenericChunk {
+		data_length: (phys_data.len() as u32).to_be_bytes(),
+		chunk_type: *b"pHYs",
+		crc: crc::calculate_crc(b"pHYs".iter().chain(phys_data.iter())).to_be_bytes(),
+		data: phys_data,
+	};
+	raw_dmi.other_chunks = Some(vec![phys_chunk.clone()]);
+	let mut original = vec![];
+	raw_dmi.save(&mut original).expect("Unable to save dmi");
+
+	let mut icon = icon::Icon::load_meta(&original[..]).expect("Unable to load metadata");
+	icon.rename_state("walk", "run");
+	let mut saved = vec![];
+	icon
+		.save_preserving(&original[..], &mut saved)
+		.expect("Unable to save icon");
+
+	let saved_raw_dmi = RawDmi::load(&saved[..]).expect("Unable to reload dmi");
+	assert_eq!(saved_raw_dmi.other_chunks, Some(vec![phys_chunk]));
+	assert_eq!(saved_raw_dmi.chunks_idat, raw_dmi.chunks_idat);
+	let saved_icon = icon::Icon::load_meta(&saved[..]).expect("Unable to reload metadata");
+	assert_eq!(saved_icon, icon);
+
+	icon.states[0].frames = 2;
+	assert!(icon.save_preserving(&original[..], &mut vec![]).is_err());
+}
+
+#[test]
+fn save_preserving_chunk_order() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let raw_dmi =
+		RawDmi::load(&dmi_with_description(32, 32, description)[..]).expect("Unable to load dmi");
+	let generic_chunk = |chunk_type: [u8; 4], data: Vec<u8>| chunk::RawGenericChunk {
+		data_length: (data.len() as u32).to_be_bytes(),
+		chunk_type,
+		crc: crc::calculate_crc(chunk_type.iter().chain(data.iter())).to_be_bytes(),
+		data,
+	};
+	let mut ztxt_bytes = vec![];
+	raw_dmi
+		.chunk_ztxt
+		.as_ref()
+		.expect("No zTXt chunk")
+		.save(&mut ztxt_bytes)
+		.expect("Unable to save zTXt chunk");
+	let ztxt_chunk =
+		chunk::RawGenericChunk::load(&mut &ztxt_bytes[..]).expect("Unable to load zTXt chunk");
+	let mut iend_bytes = vec![];
+	raw_dmi
+		.chunk_iend
+		.save(&mut iend_bytes)
+		.expect("Unable to save IEND chunk");
+	let iend_chunk =
+		chunk::RawGenericChunk::load(&mut &iend_bytes[..]).expect("Unable to load IEND chunk");
+
+	// Ancillary chunks before the palette and after the image data, where `RawDmi::save` wouldn't put them.
+	let chunks = [
+		vec![raw_dmi.chunk_ihdr.clone()],
+		vec![
+			generic_chunk(*b"gAMA", vec![0, 0, 177, 143]),
+			generic_chunk(*b"sRGB", vec![0]),
+			generic_chunk(*b"PLTE", vec![0, 0, 0]),
+			ztxt_chunk,
+		],
+		raw_dmi.chunks_idat.clone(),
+		vec![
+			generic_chunk(*b"tEXt", b"Comment\0after the image data".to_vec()),
+			iend_chunk,
+		],
+	]
+	.concat();
+	let mut original = raw_dmi.header.to_vec();
+	for chunk in &chunks {
+		chunk.save(&mut original).expect("Unable to save chunk");
+	}
+
+	let mut icon = icon::Icon::load_meta(&original[..]).expect("Unable to load metadata");
+	icon.rename_state("walk", "run");
+	let mut saved = vec![];
+	icon
+		.save_preserving(&original[..], &mut saved)
+		.expect("Unable to save icon");
+
+	let chunk_types = |dmi: &[u8]| -> Vec<[u8; 4]> {
+		read_chunks(dmi)
+			.expect("Unable to read chunks")
+			.iter()
+			.map(|chunk| chunk.chunk_type)
+			.collect()
+	};
+	assert_eq!(chunk_types(&saved), chunk_types(&original));
+	let saved_icon = icon::Icon::load_meta(&saved[..]).expect("Unable to reload metadata");
+	assert_eq!(saved_icon, icon);
+}
+
+#[test]
+fn strip_dmi_metadata() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let mut png = vec![];
+	strip_metadata(&dmi[..], &mut png).expect("Unable to strip metadata");
+
+	let raw_png = RawDmi::load(&png[..]).expect("Unable to load png");
+	assert!(raw_png.chunk_ztxt.is_none());
+	assert_eq!(
+		raw_png.chunks_idat,
+		RawDmi::load(&dmi[..])
+			.expect("Unable to load dmi")
+			.chunks_idat
+	);
+	image::load_from_memory_with_format(&png, image::ImageFormat::Png).expect("Invalid png");
+	match icon::Icon::load_meta(&png[..]) {
+		Err(error::DmiError::Generic(message)) => assert!(message.contains("Description chunk found")),
+		other => panic!("Unexpected result: {:?}", other),
+	};
+
+	assert!(strip_metadata(&b"not a png"[..], &mut vec![]).is_err());
+}
+
+#[test]
+fn text_description() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\n# END DMI\n";
+	let ztxt_icon = icon::Icon::load_meta(&dmi_with_description(64, 64, description)[..])
+		.expect("Unable to load zTXt metadata");
+	let text_dmi = dmi_with_text_description(64, 64, description);
+	let text_icon = icon::Icon::load_meta(&text_dmi[..]).expect("Unable to load tEXt metadata");
+	assert_eq!(text_icon, ztxt_icon);
+	let text_icon = icon::Icon::load(&text_dmi[..]).expect("Unable to load tEXt dmi");
+	assert_eq!(text_icon.states[0].images.len(), 4);
+
+	let mut png = vec![];
+	strip_metadata(&text_dmi[..], &mut png).expect("Unable to strip metadata");
+	assert!(icon::Icon::load_meta(&png[..]).is_err());
+
+	let mut saved = vec![];
+	text_icon
+		.save_preserving(&text_dmi[..], &mut saved)
+		.expect("Unable to save icon");
+	let saved_raw_dmi = RawDmi::load(&saved[..]).expect("Unable to reload dmi");
+	assert!(saved_raw_dmi.chunk_ztxt.is_some());
+	assert_eq!(saved_raw_dmi.other_chunks, None);
+}
+
+#[test]
+fn itxt_description() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\n# END DMI\n";
+	let ztxt_icon = icon::Icon::load_meta(&dmi_with_description(64, 64, description)[..])
+		.expect("Unable to load zTXt metadata");
+	for &compressed in &[false, true] {
+		let itxt_dmi = dmi_with_itxt_description(64, 64, description, compressed);
+		let itxt_icon = icon::Icon::load_meta(&itxt_dmi[..]).expect("Unable to load iTXt metadata");
+		assert_eq!(itxt_icon, ztxt_icon);
+
+		let mut png = vec![];
+		strip_metadata(&itxt_dmi[..], &mut png).expect("Unable to strip metadata");
+		assert!(icon::Icon::load_meta(&png[..]).is_err());
+
+		let mut saved = vec![];
+		itxt_icon
+			.save_preserving(&itxt_dmi[..], &mut saved)
+			.expect("Unable to save icon");
+		let saved_raw_dmi = RawDmi::load(&saved[..]).expect("Unable to reload dmi");
+		assert!(saved_raw_dmi.chunk_ztxt.is_some());
+		assert_eq!(saved_raw_dmi.other_chunks, None);
+	}
+
+	let truncated = dmi_with_text_chunk(64, 64, *b"iTXt", b"Description\0\x01\0en\0".to_vec());
+	assert!(icon::Icon::load_meta(&truncated[..]).is_err());
+}
+
+#[test]
+fn all_frames() {
+	let icon = icon::Icon::load(load_test_icon()).expect("Unable to load dmi");
+	let frames = icon.all_frames();
+	assert_eq!(frames.len(), icon.total_cells() as usize);
+	for (cell, frame) in &frames {
+		assert_eq!(
+			Some(frame),
+			icon.frame(&cell.state.name, cell.dir, cell.frame).as_ref()
+		);
+	}
+
+	let meta = icon::Icon::load_meta(load_test_icon()).expect("Unable to load metadata");
+	assert!(meta.all_frames().is_empty());
+}
+
+#[test]
+fn all_frames_sheet_order() {
+	let icon = icon::Icon::load(load_test_icon()).expect("Unable to load dmi");
+	let mut dmi = vec![];
+	icon.save(&mut dmi).expect("Unable to save dmi");
+	let sheet = image::load_from_memory(&dmi).expect("Unable to decode sheet");
+	let (columns, _) = icon.recommended_dimensions();
+
+	let frames = icon.all_frames();
+	assert_eq!(frames.len(), icon.total_cells() as usize);
+	for (index, (_, frame)) in frames.iter().enumerate() {
+		let index = index as u32;
+		let cell = sheet
+			.crop_imm(
+				(index % columns) * icon.width,
+				(index / columns) * icon.height,
+				icon.width,
+				icon.height,
+			)
+			.to_rgba8();
+		assert_eq!(frame, &cell);
+	}
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn all_frames_par() {
+	let icon = icon::Icon::load(load_test_icon()).expect("Unable to load dmi");
+	assert_eq!(icon.all_frames_par(), icon.all_frames());
+}
+
+#[test]
+fn icon_from_frames() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 16\n\theight = 16\nstate = \"red\"\n\tdirs = 1\n\tframes = 1\nstate = \"blue\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 16, description);
+	let metadata = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let red = image::RgbaImage::from_pixel(16, 16, image::Rgba([255, 0, 0, 255]));
+	let blue = image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 255, 255]));
+
+	let icon = icon::Icon::from_frames(metadata.clone(), vec![red.clone(), blue.clone()])
+		.expect("Unable to build icon");
+	let mut saved = vec![];
+	icon.save(&mut saved).expect("Unable to save icon");
+	let reloaded_icon = icon::Icon::load(&saved[..]).expect("Unable to reload icon");
+	assert_eq!(
+		reloaded_icon.frame("red", icon::Dir::South, 0),
+		Some(red.clone())
+	);
+	assert_eq!(reloaded_icon.frame("blue", icon::Dir::South, 0), Some(blue));
+
+	assert!(icon::Icon::from_frames(metadata.clone(), vec![red.clone()]).is_err());
+	let small = image::RgbaImage::new(8, 8);
+	assert!(icon::Icon::from_frames(metadata, vec![red, small]).is_err());
+}
+
+#[test]
+fn no_panics_on_malformed_input() {
+	// A chunk claiming more data than the file holds.
+	let mut dmi = dmi_with_description(
+		32,
+		32,
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n# END DMI\n",
+	);
+	dmi[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+	assert!(icon::Icon::load(&dmi[..]).is_err());
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"huge\"\n\tdirs = 8\n\tframes = 4000000000\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(icon::Icon::load_meta(&dmi[..]).is_err());
+	assert!(icon::Icon::load(&dmi[..]).is_err());
+
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 1\n\theight = 1\nstate = \"huge\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let mut raw_dmi =
+		RawDmi::load(&dmi_with_description(32, 32, description)[..]).expect("Unable to load dmi");
+	// A spritesheet of 2^31 by 2^31 single pixel sprites, more than fits an u32.
+	raw_dmi.chunk_ihdr.data[0..8].copy_from_slice(&[128, 0, 0, 0, 128, 0, 0, 0]);
+	raw_dmi.chunk_ihdr.crc = crc::calculate_crc(
+		raw_dmi
+			.chunk_ihdr
+			.chunk_type
+			.iter()
+			.chain(raw_dmi.chunk_ihdr.data.iter()),
+	)
+	.to_be_bytes();
+	let mut dmi = vec![];
+	raw_dmi.save(&mut dmi).expect("Unable to save dmi");
+	assert!(icon::Icon::load_meta(&dmi[..]).is_ok());
+}
+
+#[test]
+fn image_count_overflow() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"huge\"\n\tdirs = 4\n\tframes = 4000000000\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	assert!(matches!(
+		metadata_error(icon::Icon::load_meta(&dmi[..])),
+		(
+			5,
+			error::DmiError::ImageCountOverflow {
+				frames: 4000000000,
+				dirs: 4
+			}
+		)
+	));
+
+	let huge_state = icon::IconState {
+		dirs: 4,
+		frames: 4000000000,
+		..Default::default()
+	};
+	assert_eq!(huge_state.image_count(), u32::MAX);
+	assert!(huge_state.checked_image_count().is_err());
+	assert!(huge_state.validate().is_err());
+}
+
+#[test]
+fn byond_limits() {
+	let icon_state = icon::IconStateBuilder::new()
+		.name("spin")
+		.dirs(8)
+		.frames(8)
+		.delay(vec![1.0; 8])
+		.build()
+		.expect("Unable to build spin state");
+	let icon = icon::Icon::from_state(256, 256, icon_state);
+	assert_eq!(icon.recommended_dimensions(), (8, 8));
+	assert!(icon
+		.validate_byond_limits(&icon::ByondLimits::default())
+		.is_ok());
+
+	let limits = icon::ByondLimits {
+		max_sheet_width: 1024,
+		..Default::default()
+	};
+	let errors = icon
+		.validate_byond_limits(&limits)
+		.expect_err("Sheet width limit not tripped");
+	assert_eq!(errors.len(), 1);
+	assert!(matches!(
+		errors[0],
+		error::DmiError::ExceedsLimit {
+			limit: "spritesheet width",
+			value: 2048,
+			max: 1024
+		}
+	));
+
+	let limits = icon::ByondLimits {
+		max_states: 0,
+		max_cells: 63,
+		max_sheet_width: 1024,
+		max_sheet_height: 1024,
+	};
+	let errors = icon
+		.validate_byond_limits(&limits)
+		.expect_err("Limits not tripped");
+	assert_eq!(errors.len(), 4);
+	assert_eq!(
+		errors[1].to_string(),
+		"Exceeded the cell count limit: 64 (maximum 63)"
+	);
+}
+
+#[test]
+fn unknown_keys() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n\tpalette = 16\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n\tfuture = \"lmao\"\nstate = \"run\"\n\tdirs = 1\n\tframes = 1\n\tfuture = \"rofl\"\n\tpalette = 8\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let unknown_keys: Vec<String> = icon.unknown_keys().into_iter().collect();
+	assert_eq!(unknown_keys, vec!["future", "palette"]);
+
+	let icon = icon::Icon::load_meta(load_test_icon()).expect("Unable to load metadata");
+	assert!(icon.unknown_keys().is_empty());
+}
+
+#[test]
+fn validate_icon() {
+	let mut icon = icon::Icon::load_meta(load_test_icon()).expect("Unable to load metadata");
+	assert!(icon.validate().is_ok());
+
+	icon.width = 0;
+	icon.states[1].dirs = 3;
+	let errors = icon.validate().expect_err("Invalid icon validated");
+	assert_eq!(errors.len(), 2);
+	assert!(matches!(errors[0], error::DmiError::Generic(_)));
+	assert!(matches!(errors[1], error::DmiError::InvalidDirs(3)));
+}
+
+#[test]
+fn load_meta_from_text() {
+	let icon = icon::Icon::load_meta(load_test_icon()).expect("Unable to load metadata");
+	let description = icon.to_string();
+	let text_icon =
+		icon::Icon::load_meta(description.as_bytes()).expect("Unable to load metadata text");
+	assert_eq!(text_icon, icon);
+
+	assert!(icon::Icon::load_meta(&b"# BEGIN DMI\nversion = 4.0\n"[..]).is_err());
+	assert!(icon::Icon::load_meta(&[0xFF, 0xFE][..]).is_err());
+}
+
+#[test]
+fn state_spans() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# note\n\nstate = \"run\"\r\n\tdirs = 4\r\n\tframes = 1\r\n# END DMI\n";
+	let (icon, spans) =
+		icon::Icon::metadata_with_spans(description).expect("Unable to load metadata");
+	assert_eq!(spans.len(), icon.states.len());
+	assert_eq!(
+		&description[spans[0].clone()],
+		"state = \"walk\"\n\tdirs = 1\n\tframes = 1"
+	);
+	assert_eq!(
+		&description[spans[1].clone()],
+		"state = \"run\"\r\n\tdirs = 4\r\n\tframes = 1"
+	);
+	assert_eq!(icon.states[1].dirs, 4);
+
+	assert!(icon::Icon::metadata_with_spans("# BEGIN DMI\nversion = 4.0\n").is_err());
+}
+
+#[test]
+fn comment_lines() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# note\nstate = \"run\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let names: Vec<&str> = icon
+		.states
+		.iter()
+		.map(|icon_state| icon_state.name.as_str())
+		.collect();
+	assert_eq!(names, vec!["walk", "run"]);
+	assert!(icon.states[0].unknown_settings.is_none());
+	assert_eq!(
+		icon.states[1].comments,
+		Some(vec![(0, "# note".to_string())])
+	);
+	assert!(icon.comments.is_none());
+	assert_eq!(icon.to_string(), description);
+
+	// Text after the trailer marker doesn't make it a comment.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# END DMI foo\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states.len(), 1);
+	assert!(icon.states[0].comments.is_none());
+}
+
+#[test]
+fn comment_round_trip() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n# player sprites\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n# TODO: redo\n# the run frames\nstate = \"run\"\n\tdirs = 1\n\tframes = 1\n# last\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.states[0].comments,
+		Some(vec![(0, "# player sprites".to_string())])
+	);
+	assert_eq!(
+		icon.states[1].comments,
+		Some(vec![
+			(0, "# TODO: redo".to_string()),
+			(0, "# the run frames".to_string()),
+			(3, "# last".to_string()),
+		])
+	);
+	assert_eq!(icon.to_string(), description);
+
+	let mut saved = vec![];
+	icon
+		.save_preserving(&dmi[..], &mut saved)
+		.expect("Unable to save icon");
+	let saved_icon = icon::Icon::load_meta(&saved[..]).expect("Unable to reload metadata");
+	assert_eq!(saved_icon, icon);
+
+	// Comments go along with the state they belong to.
+	let mut icon = icon;
+	icon.states.swap(0, 1);
+	assert!(icon.to_string().contains(
+		"# the run frames\nstate = \"run\"\n\tdirs = 1\n\tframes = 1\n# last\n# player sprites\n"
+	));
+	icon.states.pop();
+	assert!(!icon.to_string().contains("# player sprites"));
+}
+
+#[test]
+fn comments_within_states() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n# square sprites\n\theight = 32\nstate = \"walk\"\n\tdirs = 4\n# two frames, see below\n\tframes = 2\n\tdelay = 1,2\n# slow\n\tloop = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 128, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(
+		icon.comments,
+		Some(vec![(2, "# square sprites".to_string())])
+	);
+	assert_eq!(
+		icon.states[0].comments,
+		Some(vec![
+			(2, "# two frames, see below".to_string()),
+			(4, "# slow".to_string()),
+		])
+	);
+	assert_eq!(icon.to_string(), description);
+
+	// Without states, comments before the trailer stay after the header.
+	let description =
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n# nothing yet\n# END DMI\n";
+	let icon = icon::Icon::load_meta(description.as_bytes()).expect("Unable to load metadata");
+	assert_eq!(icon.comments, Some(vec![(3, "# nothing yet".to_string())]));
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn minor_version() {
+	let description = "# BEGIN DMI\nversion = 4.1\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load 4.1 metadata");
+	assert_eq!(icon.version.to_string(), "4.1");
+	assert_eq!(icon.version.major, 4);
+	assert_eq!(icon.to_string(), description);
+
+	// The leading zeros of the minor version are kept when saving.
+	let description = description.replace("version = 4.1", "version = 4.01");
+	let dmi = dmi_with_description(32, 32, &description);
+	let icon = icon::Icon::load(&dmi[..]).expect("Unable to load 4.01 icon");
+	assert_eq!(icon.version.minor, 1);
+	assert_eq!(icon.to_string(), description);
+	let mut saved = vec![];
+	icon.save(&mut saved).expect("Unable to save icon");
+	let reloaded_icon = icon::Icon::load(&saved[..]).expect("Unable to reload icon");
+	assert_eq!(reloaded_icon.version.to_string(), "4.01");
+}
+
+#[test]
+fn add_state() {
+	let mut icon = icon::IconBuilder::new(32, 32)
+		.build()
+		.expect("Invalid icon");
+	let blink = icon::IconState {
+		name: "blink".to_string(),
+		frames: 2,
+		delay: Some(vec![1.0, 2.0]),
+		images: vec![image::DynamicImage::new_rgba8(32, 32); 2],
+		..Default::default()
+	};
+	icon.add_state(blink.clone()).expect("Unable to add state");
+	assert_eq!(icon.states, vec![blink.clone()]);
+
+	let mismatched_delays = icon::IconState {
+		delay: Some(vec![1.0]),
+		..blink.clone()
+	};
+	assert!(icon.add_state(mismatched_delays).is_err());
+	let small_images = icon::IconState {
+		images: vec![image::DynamicImage::new_rgba8(16, 16); 2],
+		..blink
+	};
+	assert!(icon.add_state(small_images).is_err());
+	assert_eq!(icon.states.len(), 1);
+}
+
+#[test]
+fn loop_count() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"forever\"\n\tdirs = 1\n\tframes = 2\n\tdelay = 1,1\n\tloop = 0\nstate = \"thrice\"\n\tdirs = 1\n\tframes = 2\n\tdelay = 1,1\n\tloop = 3\nstate = \"unset\"\n\tdirs = 1\n\tframes = 2\n\tdelay = 1,1\n# END DMI\n";
+	let dmi = dmi_with_description(96, 64, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	let loops: Vec<(bool, Option<u32>)> = icon
+		.states
+		.iter()
+		.map(|icon_state| (icon_state.loops_forever(), icon_state.loop_count()))
+		.collect();
+	assert_eq!(loops, vec![(true, None), (false, Some(3)), (true, None)]);
+}
+
+#[test]
+fn trailing_content_after_end() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"mob\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n\n\0garbage = \"lmao\"\n";
+	let dmi = dmi_with_description(32, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states.len(), 1);
+	assert!(icon.states[0].unknown_settings.is_none());
+}
+
+#[test]
+fn normalized_delays() {
+	let state = |frames, delay| icon::IconState {
+		frames,
+		delay,
+		..Default::default()
+	};
+	assert_eq!(
+		state(4, Some(vec![2.0, 3.0])).normalized_delays(),
+		vec![2.0, 3.0, 1.0, 1.0]
+	);
+	assert_eq!(
+		state(2, Some(vec![2.0, 3.0, 4.0])).normalized_delays(),
+		vec![2.0, 3.0]
+	);
+	assert_eq!(state(3, None).normalized_delays(), vec![1.0, 1.0, 1.0]);
+}
+
+#[test]
+fn empty_state_name() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"\"\n\tdirs = 1\n\tframes = 1\nstate = \"named\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let icon = icon::Icon::load_meta(&dmi[..]).expect("Unable to load metadata");
+	assert_eq!(icon.states[0].name, "");
+	assert_eq!(icon.state("").map(|icon_state| icon_state.dirs), Some(1));
+	assert_eq!(icon.to_string(), description);
+}
+
+#[test]
+fn split_by_state() {
+	let icon = icon::Icon::load(load_test_icon()).expect("Unable to load dmi");
+	let split_icons = icon.split_by_state();
+	assert_eq!(split_icons.len(), 2);
+	for (split_icon, icon_state) in split_icons.iter().zip(&icon.states) {
+		assert_eq!(split_icon.version, icon.version);
+		assert_eq!(
+			(split_icon.width, split_icon.height),
+			(icon.width, icon.height)
+		);
+		assert_eq!(split_icon.states, vec![icon_state.clone()]);
+	}
+}
+
+#[test]
+fn state_sheet() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 16\n\theight = 16\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\nstate = \"walk\"\n\tdirs = 4\n\tframes = 1\nstate = \"idle\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let metadata = icon::Icon::load_meta(&dmi_with_description(48, 32, description)[..])
+		.expect("Unable to load metadata");
+	let frames = (0..6)
+		.map(|index| image::RgbaImage::from_pixel(16, 16, image::Rgba([index * 40, 0, 0, 255])))
+		.collect();
+	let icon = icon::Icon::from_frames(metadata, frames).expect("Unable to build icon");
+
+	let walk = icon.state_sheet("walk").expect("No walk state");
+	assert_eq!(walk.states.len(), 1);
+	assert_eq!(walk.total_cells(), 4);
+	assert_eq!(walk.recommended_dimensions(), (2, 2));
+	assert_eq!(walk.states[0], icon.states[1]);
+
+	let mut dmi = vec![];
+	walk.save(&mut dmi).expect("Unable to save walk sheet");
+	let reloaded = icon::Icon::load(&dmi[..]).expect("Unable to reload walk sheet");
+	assert_eq!(reloaded, walk);
+	assert!(icon.state_sheet("run").is_none());
+}
+
+#[test]
+fn compression_levels() {
+	let icon = icon::Icon::load(load_test_icon()).expect("Unable to load dmi");
+	let mut fastest = vec![];
+	icon
+		.save_with_compression(&mut fastest, 0)
+		.expect("Unable to save at level 0");
+	let mut smallest = vec![];
+	icon
+		.save_with_compression(&mut smallest, 9)
+		.expect("Unable to save at level 9");
+	let fastest_icon = icon::Icon::load(&fastest[..]).expect("Unable to reload level 0 dmi");
+	let smallest_icon = icon::Icon::load(&smallest[..]).expect("Unable to reload level 9 dmi");
+	assert_eq!(fastest_icon, icon);
+	assert_eq!(smallest_icon, icon);
+
+	let mut saved = vec![];
+	assert!(matches!(
+		icon.save_with_compression(&mut saved, 10),
+		Err(error::DmiError::InvalidValue {
+			setting: "compression level",
+			..
+		})
+	));
+
+	let description = icon.to_string();
+	assert_eq!(
+		ztxt::encode_with_level(description.as_bytes(), ztxt::DEFAULT_COMPRESSION_LEVEL)
+			.expect("Unable to compress"),
+		ztxt::encode(description.as_bytes())
+	);
+}
+
+#[test]
+fn capitalized_state_key() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"a\"\n\tdirs = 1\n\tframes = 1\nState = \"b\"\n\tdirs = 1\n\tframes = 1\n# END DMI\n";
+	let dmi = dmi_with_description(64, 32, description);
+	let (icon, warnings) = icon::Icon::load_with_warnings(&dmi[..]).expect("Unable to load dmi");
+	let names: Vec<&str> = icon
+		.states
+		.iter()
+		.map(|state| state.name.as_str())
+		.collect();
+	assert_eq!(names, ["a", "b"]);
+	assert_eq!(
+		warnings,
+		vec![icon::Warning::CapitalizedKey("State".to_string())]
+	);
+}
+
+#[test]
+fn load_warnings() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\tWidth = 32\n\theight = 32\n\tpalette = 16\nstate = \"blink\"\n\tdirs = 1\n\tframes = 3\n\tdelay = 1,2\n\tfuture = 1\n# END DMI\n";
+	let dmi = dmi_with_description(96, 32, description);
+	let (icon, warnings) = icon::Icon::load_with_warnings(&dmi[..]).expect("Unable to load dmi");
+	assert_eq!(icon.states[0].images.len(), 3);
+	assert_eq!(
+		warnings,
+		vec![
+			icon::Warning::CapitalizedKey("Width".to_string()),
+			icon::Warning::UnknownSetting {
+				state: None,
+				setting: "palette".to_string()
+			},
+			icon::Warning::UnknownSetting {
+				state: Some("blink".to_string()),
+				setting: "future".to_string()
+			},
+			icon::Warning::DelayMismatch {
+				state: "blink".to_string(),
+				frames: 3,
+				delays: 2
+			},
+		]
+	);
+	assert_eq!(
+		warnings[3].to_string(),
+		"State \"blink\" has 3 frames but 2 delays"
+	);
+
+	let (_, warnings) = icon::Icon::load_with_warnings(load_test_icon()).expect("Unable to load dmi");
+	assert!(warnings.is_empty());
+}
+
+#[test]
+fn missing_delay_warning() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"still\"\n\tdirs = 1\n\tframes = 1\nstate = \"spin\"\n\tdirs = 1\n\tframes = 4\n# END DMI\n";
+	let dmi = dmi_with_description(160, 32, description);
+	let (icon, warnings) = icon::Icon::load_with_warnings(&dmi[..]).expect("Unable to load dmi");
+	assert_eq!(icon.states[1].delay, None);
+	assert_eq!(
+		warnings,
+		vec![icon::Warning::MissingDelay {
+			state: "spin".to_string(),
+			frames: 4
+		}]
+	);
+	assert_eq!(
+		warnings[0].to_string(),
+		"State \"spin\" has 4 frames but no delay"
+	);
+}
+
+#[test]
+fn round_trip_fixtures() {
+	let descriptions = [
+		// Animated states with every known setting, plus unknown ones in both the header and a state.
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\n\tpalette = 16\nstate = \"walk\"\n\tdirs = 4\n\tframes = 3\n\tdelay = 1,0.1,2.5\n\tloop = 2\n\trewind = 1\n\thotspot = 13,12,1\n\thotspot = 2,3,4\n\tfuture = \"lmao\"\n\tzzz = 1\n\taaa = 2\nstate = \"walk\"\n\tdirs = 1\n\tframes = 1\n\tmovement = 1\n# END DMI\n",
+		// Hand-edited: spaces, capitals, comments, CRLF line endings, a bare version and escaped names.
+		"# BEGIN DMI\r\nversion = 4\r\n  Width = 32\r\n  height = 32\r\n# note\r\nstate = \"say \\\"hi\\\"\"\r\n  DIRS = 8\r\n  frames = 2\r\n  delay = 1, 1.5\r\n\r\nstate = \"\"\r\n  dirs = 1\r\n  frames = 1\r\n# END DMI\r\n",
+		// Legacy version without any state.
+		"# BEGIN DMI\nversion = 3.0\n\twidth = 16\n\theight = 24\n# END DMI\n",
+		// Separators within values.
+		"# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"a = b\"\n\tdirs = 1\n\tframes = 1\n\tequation = \"x = y\"\n# END DMI\n",
+	];
+	let mut icons: Vec<icon::Icon> = descriptions
+		.iter()
+		.map(|description| {
+			icon::Icon::load_meta(description.as_bytes()).expect("Unable to load fixture")
+		})
+		.collect();
+	icons.push(icon::Icon::load_meta(load_test_icon()).expect("Unable to load metadata"));
+
+	for icon in icons {
+		let written = icon.to_string();
+		let reloaded_icon =
+			icon::Icon::load_meta(written.as_bytes()).expect("Unable to reload metadata");
+		assert_eq!(reloaded_icon, icon);
+		assert_eq!(reloaded_icon.to_string(), written);
+	}
+	// Canonical text is written back byte for byte.
+	for &description in &[descriptions[0], descriptions[2], descriptions[3]] {
+		assert_eq!(
+			icon::Icon::load_meta(description.as_bytes())
+				.expect("Unable to load fixture")
+				.to_string(),
+			description
+		);
+	}
+
+	// Non-finite delays load and are written back as found, only `validate` rejects them.
+	// NaN never equals itself, so the delays are compared bit for bit.
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"blink\"\n\tdirs = 1\n\tframes = 3\n\tdelay = 1,NaN,inf\n# END DMI\n";
+	let icon = icon::Icon::load_meta(description.as_bytes()).expect("Unable to load fixture");
+	assert_eq!(icon.to_string(), description);
+	let reloaded_icon =
+		icon::Icon::load_meta(icon.to_string().as_bytes()).expect("Unable to reload metadata");
+	let delay_bits = |icon: &icon::Icon| -> Vec<u32> {
+		icon.states[0]
+			.delay
+			.iter()
+			.flatten()
+			.map(|delay| delay.to_bits())
+			.collect()
+	};
+	assert_eq!(delay_bits(&reloaded_icon), delay_bits(&icon));
+	assert!(matches!(
+		icon.states[0].validate(),
+		Err(error::DmiError::InvalidValue {
+			setting: "delay",
+			..
+		})
+	));
+}
+
+#[test]
+fn minimal_delay_formatting() {
+	let description = "# BEGIN DMI\nversion = 4.0\n\twidth = 32\n\theight = 32\nstate = \"blink\"\n\tdirs = 1\n\tframes = 4\n\tdelay = 1,2,5.4,3\n# END DMI\n";
+	let icon = icon::Icon::load_meta(description.as_bytes()).expect("Unable to load metadata");
+	assert!(icon.to_string().contains("\tdelay = 1,2,5.4,3\n"));
+
+	let icon_state = icon::IconState {
+		frames: 3,
+		delay: Some(vec![1.0, 1.2, 0.1]),
+		..Default::default()
+	};
+	assert!(icon_state.to_string().contains("\tdelay = 1,1.2,0.1\n"));
+}
+
+#[test]
+fn version_ordering() {
+	let version = |text: &str| text.parse::<icon::DmiVersion>().expect("Invalid version");
+	assert_eq!(version("4.0"), icon::DmiVersion::new(4, 0));
+	assert_eq!(version("4"), version("4.0"));
+	assert_eq!(version("4.12"), icon::DmiVersion::new(4, 12));
+	assert!(version("3.0") < version("4.0"));
+	assert!(version("4.2") < version("4.12"));
+	assert!(version("4.12") < version("5.0"));
+	assert_eq!(version("4.12").to_string(), "4.12");
+	assert_eq!(version("4.01").to_string(), "4.01");
+	assert_eq!(version("4.01"), version("4.1"));
+	assert!(version("4.01") < version("4.2"));
+	assert_eq!(icon::DmiVersion::new(4, 1).to_string(), "4.1");
+	assert_eq!(icon::DmiVersion::default().to_string(), "4.0");
+	for invalid_version in &["four", "4.", "-4.0", "4.0.1", "256.0"] {
+		assert!(invalid_version.parse::<icon::DmiVersion>().is_err());
+	}
+}